@@ -0,0 +1,1182 @@
+use std::{env, sync::Arc};
+use std::io::{BufRead, BufReader};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::chat::{ChatMessage, ContentPart, Role, ToolCall};
+use crate::config::ProviderConfig;
+use crate::error::LlmError;
+use crate::llm::RetryPolicy;
+use crate::sse::SseEvents;
+
+/// Everything that decides the reply. It serializes to the cache key, see
+/// `cache::key`, while each backend builds its own wire payload from it.
+#[derive(Serialize)]
+pub struct ChatRequest {
+    pub model: String,
+    pub messages: Vec<ChatMessage>,
+    pub max_tokens: i32,
+    pub sampling: Sampling,
+    pub response_format: Option<ResponseFormat>,
+    pub tools: Vec<ToolSpec>
+}
+
+/// How the next tokens get picked. Anything left unset is up to the
+/// provider's defaults, and settings a provider doesn't know are left out.
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+pub struct Sampling {
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub seed: Option<u64>,
+    pub stop: Vec<String>,
+    pub presence_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>
+}
+
+/// A function the model may call, described by a JSON Schema of its arguments.
+#[derive(Serialize, Clone, Debug)]
+pub struct ToolSpec {
+    pub name: String,
+    pub description: String,
+    pub parameters: Value
+}
+
+/// Asks the provider for a JSON reply instead of prose.
+#[derive(Serialize, Clone, Debug)]
+pub enum ResponseFormat {
+    Json,
+    /// JSON matching a JSON Schema. Providers that can't enforce a schema
+    /// fall back to plain JSON mode.
+    Schema(Value)
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Completion {
+    pub content: String,
+    pub finish_reason: Option<String>,
+    pub usage: Option<Usage>,
+    pub tool_calls: Vec<ToolCall>
+}
+
+impl Completion {
+    fn empty() -> Self {
+        Completion { content: String::new(), finish_reason: None, usage: None, tool_calls: Vec::new() }
+    }
+
+    /// Whether the provider stopped because it hit `max_tokens`.
+    pub fn truncated(&self) -> bool {
+        matches!(self.finish_reason.as_deref(), Some("length") | Some("max_tokens"))
+    }
+}
+
+/// Token counts as reported by the provider.
+#[derive(Serialize, Deserialize, Clone, Copy, Default, Debug, PartialEq)]
+pub struct Usage {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32
+}
+
+/// A chat completion provider. `LLM` only talks to this trait, so the same
+/// prompts can be sent to Groq, OpenAI, a local Ollama or Anthropic.
+pub trait ChatBackend: Send + Sync {
+    fn name(&self) -> &str;
+    /// URL the requests go to, which tells apart servers speaking the same
+    /// protocol.
+    fn endpoint(&self) -> &str;
+    fn complete(&self, request: &ChatRequest) -> Result<Completion, LlmError>;
+
+    /// Like `complete`, but hands each piece of text to `on_delta` as soon as
+    /// the provider sends it. The returned completion holds the full text.
+    fn stream(&self, request: &ChatRequest, on_delta: &mut dyn FnMut(&str)) -> Result<Completion, LlmError>;
+
+    /// Whether a request ending in an assistant message has the reply carry
+    /// on from it, rather than answering anew.
+    fn supports_prefill(&self) -> bool {
+        false
+    }
+}
+
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Provider {
+    Groq,
+    Openai,
+    Ollama,
+    Anthropic
+}
+
+impl Provider {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Provider::Groq => "groq",
+            Provider::Openai => "openai",
+            Provider::Ollama => "ollama",
+            Provider::Anthropic => "anthropic"
+        }
+    }
+}
+
+pub fn from_provider(provider: Provider, config: &ProviderConfig) -> Arc<dyn ChatBackend> {
+    match provider {
+        Provider::Groq => Arc::new(OpenAICompatible::groq(config)),
+        Provider::Openai => Arc::new(OpenAICompatible::openai(config)),
+        Provider::Ollama => Arc::new(Ollama::new(config)),
+        Provider::Anthropic => Arc::new(Anthropic::new(config))
+    }
+}
+
+/// Where a backend gets its API key from: the environment variable wins over
+/// a key written into the config file.
+struct Credentials {
+    var: String,
+    key: Option<String>
+}
+
+impl Credentials {
+    fn new(config: &ProviderConfig, default_var: &str) -> Self {
+        Credentials {
+            var: config.api_key_env.clone().unwrap_or_else(|| default_var.to_string()),
+            key: config.api_key.clone()
+        }
+    }
+
+    fn get(&self, provider: &str) -> Result<String, LlmError> {
+        env::var(&self.var).ok()
+            .filter(|key| !key.trim().is_empty())
+            .or_else(|| self.key.clone())
+            .ok_or_else(|| LlmError::MissingCredentials {
+                provider: provider.to_string(),
+                var: self.var.clone()
+            })
+    }
+}
+
+fn base_url(config: &ProviderConfig, env_var: &str, default: &str) -> String {
+    config.base_url.clone()
+        .or_else(|| env::var(env_var).ok())
+        .unwrap_or_else(|| default.to_string())
+        .trim_end_matches('/')
+        .to_string()
+}
+
+// OpenAI style chat completions, also spoken by Groq and most hosted gateways.
+
+#[derive(Serialize, Debug)]
+struct Payload<'a> {
+    messages: Vec<OpenAIMessage<'a>>,
+    model: &'a str,
+    max_tokens: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    stop: &'a [String],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    presence_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<Value>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<Value>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    stream: bool,
+    // Without it a stream ends without reporting the tokens used
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<Value>
+}
+
+#[derive(Deserialize, Debug)]
+struct Choices {
+    choices: Vec<Choice>,
+    usage: Option<Usage>
+}
+
+#[derive(Deserialize, Debug)]
+struct Choice {
+    message: ResponseMessage,
+    finish_reason: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ResponseMessage {
+    // null when the model only calls tools
+    content: Option<String>,
+    #[serde(default)]
+    tool_calls: Vec<WireToolCall>
+}
+
+#[derive(Deserialize, Debug)]
+struct WireToolCall {
+    // Ollama doesn't number its calls
+    id: Option<String>,
+    function: WireFunction
+}
+
+#[derive(Deserialize, Debug)]
+struct WireFunction {
+    name: String,
+    // A JSON encoded string from OpenAI, a JSON object from Ollama
+    arguments: Value
+}
+
+impl WireToolCall {
+    fn into_tool_call(self, index: usize) -> Result<ToolCall, LlmError> {
+        let arguments = match self.function.arguments {
+            Value::String(text) if text.trim().is_empty() => json!({}),
+            Value::String(text) => serde_json::from_str(&text)?,
+            arguments => arguments
+        };
+
+        Ok(ToolCall {
+            id: self.id.unwrap_or_else(|| format!("call_{}", index)),
+            name: self.function.name,
+            arguments
+        })
+    }
+}
+
+fn tool_calls(calls: Vec<WireToolCall>) -> Result<Vec<ToolCall>, LlmError> {
+    calls.into_iter().enumerate().map(|(index, call)| call.into_tool_call(index)).collect()
+}
+
+#[derive(Serialize, Debug)]
+struct OpenAIMessage<'a> {
+    role: Role,
+    content: OpenAIContent<'a>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<&'a str>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tool_calls: Vec<OpenAIToolCall<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_call_id: Option<&'a str>
+}
+
+// Plain text goes out as a string, which every OpenAI compatible server
+// understands, and only messages with images as a list of parts.
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+enum OpenAIContent<'a> {
+    Text(String),
+    Parts(Vec<OpenAIPart<'a>>)
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum OpenAIPart<'a> {
+    Text { text: &'a str },
+    ImageUrl { image_url: OpenAIImageUrl }
+}
+
+#[derive(Serialize, Debug)]
+struct OpenAIImageUrl {
+    url: String
+}
+
+#[derive(Serialize, Debug)]
+struct OpenAIToolCall<'a> {
+    id: &'a str,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    function: OpenAIFunction<'a>
+}
+
+#[derive(Serialize, Debug)]
+struct OpenAIFunction<'a> {
+    name: &'a str,
+    // OpenAI wants the arguments as an encoded string
+    arguments: String
+}
+
+impl<'a> From<&'a ChatMessage> for OpenAIMessage<'a> {
+    fn from(message: &'a ChatMessage) -> Self {
+        let has_images = message.content.iter().any(|part| matches!(part, ContentPart::Image { .. }));
+
+        let content = if has_images {
+            OpenAIContent::Parts(message.content.iter().map(|part| match part {
+                ContentPart::Text(text) => OpenAIPart::Text { text },
+                ContentPart::Image { media_type, data } => OpenAIPart::ImageUrl {
+                    image_url: OpenAIImageUrl { url: format!("data:{};base64,{}", media_type, data) }
+                }
+            }).collect())
+        } else {
+            OpenAIContent::Text(message.text_content())
+        };
+
+        OpenAIMessage {
+            role: message.role,
+            content,
+            name: message.name.as_deref(),
+            tool_calls: message.tool_calls.iter().map(|call| OpenAIToolCall {
+                id: &call.id,
+                kind: "function",
+                function: OpenAIFunction { name: &call.name, arguments: call.arguments.to_string() }
+            }).collect(),
+            tool_call_id: message.tool_call_id.as_deref()
+        }
+    }
+}
+
+fn openai_tools(tools: &[ToolSpec]) -> Vec<Value> {
+    tools.iter().map(|tool| json!({
+        "type": "function",
+        "function": { "name": tool.name, "description": tool.description, "parameters": tool.parameters }
+    })).collect()
+}
+
+#[derive(Deserialize, Debug)]
+struct ChunkChoices {
+    choices: Vec<ChunkChoice>,
+    usage: Option<Usage>,
+    // Groq reports usage on the last chunk under its own key
+    x_groq: Option<GroqExtra>
+}
+
+#[derive(Deserialize, Debug)]
+struct GroqExtra {
+    usage: Option<Usage>
+}
+
+#[derive(Deserialize, Debug)]
+struct ChunkChoice {
+    delta: Delta,
+    finish_reason: Option<String>
+}
+
+#[derive(Deserialize, Debug)]
+struct Delta {
+    content: Option<String>
+}
+
+pub struct OpenAICompatible {
+    name: String,
+    url: String,
+    // Groq only knows `json_object`, OpenAI also takes a schema
+    json_schema: bool,
+    credentials: Credentials,
+    client: reqwest::blocking::Client,
+    retry: RetryPolicy
+}
+
+impl OpenAICompatible {
+    fn new(name: &str, base_url: &str, json_schema: bool, credentials: Credentials) -> Self {
+        OpenAICompatible {
+            name: name.to_string(),
+            url: format!("{}/chat/completions", base_url),
+            json_schema,
+            credentials,
+            client: reqwest::blocking::Client::new(),
+            retry: RetryPolicy::default()
+        }
+    }
+
+    pub fn groq(config: &ProviderConfig) -> Self {
+        let base_url = base_url(config, "GROQ_BASE_URL", "https://api.groq.com/openai/v1");
+        Self::new("groq", &base_url, false, Credentials::new(config, "GROQ_API_KEY"))
+    }
+
+    pub fn openai(config: &ProviderConfig) -> Self {
+        let base_url = base_url(config, "OPENAI_BASE_URL", "https://api.openai.com/v1");
+        Self::new("openai", &base_url, true, Credentials::new(config, "OPENAI_API_KEY"))
+    }
+}
+
+impl ChatBackend for OpenAICompatible {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn endpoint(&self) -> &str {
+        &self.url
+    }
+
+    fn complete(&self, request: &ChatRequest) -> Result<Completion, LlmError> {
+        let response: Choices = self.send(request, false)?.json()?;
+
+        let choice = response.choices.into_iter().next()
+            .ok_or(LlmError::EmptyResponse { provider: self.name.clone() })?;
+
+        Ok(Completion {
+            content: choice.message.content.unwrap_or_default(),
+            finish_reason: choice.finish_reason,
+            usage: response.usage,
+            tool_calls: tool_calls(choice.message.tool_calls)?
+        })
+    }
+
+    fn stream(&self, request: &ChatRequest, on_delta: &mut dyn FnMut(&str)) -> Result<Completion, LlmError> {
+        read_openai_stream(BufReader::new(self.send(request, true)?), on_delta)
+    }
+}
+
+/// Collects a streamed reply, handing each piece of text to `on_delta`. The
+/// usage comes in a last chunk without choices, or with Groq on the chunk
+/// that finishes the reply.
+fn read_openai_stream(reader: impl BufRead, on_delta: &mut dyn FnMut(&str)) -> Result<Completion, LlmError> {
+    let mut completion = Completion::empty();
+
+    for event in SseEvents::new(reader) {
+        let event = event?;
+
+        if event.data == "[DONE]" {
+            break;
+        }
+
+        let chunk: ChunkChoices = serde_json::from_str(&event.data)?;
+
+        if let Some(usage) = chunk.usage.or(chunk.x_groq.and_then(|extra| extra.usage)) {
+            completion.usage = Some(usage);
+        }
+
+        for choice in chunk.choices {
+            if let Some(text) = choice.delta.content {
+                on_delta(&text);
+                completion.content.push_str(&text);
+            }
+            if choice.finish_reason.is_some() {
+                completion.finish_reason = choice.finish_reason;
+            }
+        }
+    }
+
+    Ok(completion)
+}
+
+impl OpenAICompatible {
+    fn payload<'a>(&self, request: &'a ChatRequest, stream: bool) -> Payload<'a> {
+        let response_format = request.response_format.as_ref().map(|format| match format {
+            ResponseFormat::Schema(schema) if self.json_schema => json!({
+                "type": "json_schema",
+                "json_schema": { "name": "response", "schema": schema }
+            }),
+            _ => json!({ "type": "json_object" })
+        });
+
+        Payload {
+            model: &request.model,
+            messages: request.messages.iter().map(OpenAIMessage::from).collect(),
+            max_tokens: request.max_tokens,
+            temperature: request.sampling.temperature,
+            top_p: request.sampling.top_p,
+            seed: request.sampling.seed,
+            stop: &request.sampling.stop,
+            presence_penalty: request.sampling.presence_penalty,
+            frequency_penalty: request.sampling.frequency_penalty,
+            response_format,
+            tools: openai_tools(&request.tools),
+            stream,
+            stream_options: stream.then(|| json!({ "include_usage": true }))
+        }
+    }
+
+    fn send(&self, request: &ChatRequest, stream: bool) -> Result<reqwest::blocking::Response, LlmError> {
+        let key = self.credentials.get(&self.name)?;
+        let body = self.payload(request, stream);
+
+        self.retry.send(|| {
+            self.client.post(&self.url)
+                .header("Authorization", format!("Bearer {}", key))
+                .header("CONTENT_TYPE", "application/json")
+                .json(&body)
+        })
+    }
+}
+
+// Ollama's native /api/chat endpoint.
+
+#[derive(Serialize, Debug)]
+struct OllamaPayload<'a> {
+    model: &'a str,
+    messages: Vec<OllamaMessage<'a>>,
+    stream: bool,
+    // "json", or a JSON Schema to constrain the reply to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<Value>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<Value>,
+    options: OllamaOptions<'a>
+}
+
+// Images ride along as a list of base64 strings next to the text.
+#[derive(Serialize, Debug)]
+struct OllamaMessage<'a> {
+    role: Role,
+    content: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    images: Vec<&'a str>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tool_calls: Vec<OllamaToolCall<'a>>
+}
+
+#[derive(Serialize, Debug)]
+struct OllamaToolCall<'a> {
+    function: OllamaFunction<'a>
+}
+
+#[derive(Serialize, Debug)]
+struct OllamaFunction<'a> {
+    name: &'a str,
+    arguments: &'a Value
+}
+
+impl<'a> From<&'a ChatMessage> for OllamaMessage<'a> {
+    fn from(message: &'a ChatMessage) -> Self {
+        OllamaMessage {
+            role: message.role,
+            content: message.text_content(),
+            images: message.content.iter().filter_map(|part| match part {
+                ContentPart::Image { data, .. } => Some(data.as_str()),
+                ContentPart::Text(_) => None
+            }).collect(),
+            tool_calls: message.tool_calls.iter().map(|call| OllamaToolCall {
+                function: OllamaFunction { name: &call.name, arguments: &call.arguments }
+            }).collect()
+        }
+    }
+}
+
+// Ollama takes sampling settings as model options rather than top level fields.
+#[derive(Serialize, Debug)]
+struct OllamaOptions<'a> {
+    num_predict: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    stop: &'a [String],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    presence_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    frequency_penalty: Option<f32>
+}
+
+#[derive(Deserialize, Debug)]
+struct OllamaResponse {
+    message: ResponseMessage,
+    done_reason: Option<String>,
+    prompt_eval_count: Option<u32>,
+    eval_count: Option<u32>
+}
+
+// Streamed replies are newline delimited JSON rather than SSE.
+#[derive(Deserialize, Debug)]
+struct OllamaChunk {
+    message: Option<ResponseMessage>,
+    done: bool,
+    done_reason: Option<String>,
+    prompt_eval_count: Option<u32>,
+    eval_count: Option<u32>
+}
+
+fn ollama_usage(prompt_eval_count: Option<u32>, eval_count: Option<u32>) -> Option<Usage> {
+    match (prompt_eval_count, eval_count) {
+        (None, None) => None,
+        (prompt, completion) => Some(Usage {
+            prompt_tokens: prompt.unwrap_or(0),
+            completion_tokens: completion.unwrap_or(0)
+        })
+    }
+}
+
+pub struct Ollama {
+    url: String,
+    client: reqwest::blocking::Client,
+    retry: RetryPolicy
+}
+
+impl Ollama {
+    pub fn new(config: &ProviderConfig) -> Self {
+        let host = base_url(config, "OLLAMA_HOST", "http://localhost:11434");
+        Ollama {
+            url: format!("{}/api/chat", host),
+            client: reqwest::blocking::Client::new(),
+            retry: RetryPolicy::default()
+        }
+    }
+}
+
+impl ChatBackend for Ollama {
+    fn name(&self) -> &str {
+        "ollama"
+    }
+
+    fn endpoint(&self) -> &str {
+        &self.url
+    }
+
+    fn complete(&self, request: &ChatRequest) -> Result<Completion, LlmError> {
+        let response: OllamaResponse = self.send(request, false)?.json()?;
+
+        Ok(Completion {
+            content: response.message.content.unwrap_or_default(),
+            finish_reason: response.done_reason,
+            usage: ollama_usage(response.prompt_eval_count, response.eval_count),
+            tool_calls: tool_calls(response.message.tool_calls)?
+        })
+    }
+
+    fn stream(&self, request: &ChatRequest, on_delta: &mut dyn FnMut(&str)) -> Result<Completion, LlmError> {
+        let response = self.send(request, true)?;
+
+        let mut completion = Completion::empty();
+
+        for line in BufReader::new(response).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let chunk: OllamaChunk = serde_json::from_str(&line)?;
+
+            if let Some(text) = chunk.message.and_then(|message| message.content) {
+                on_delta(&text);
+                completion.content.push_str(&text);
+            }
+
+            if chunk.done {
+                completion.finish_reason = chunk.done_reason;
+                completion.usage = ollama_usage(chunk.prompt_eval_count, chunk.eval_count);
+                break;
+            }
+        }
+
+        Ok(completion)
+    }
+}
+
+impl Ollama {
+    fn payload<'a>(&self, request: &'a ChatRequest, stream: bool) -> OllamaPayload<'a> {
+        OllamaPayload {
+            model: &request.model,
+            messages: request.messages.iter().map(OllamaMessage::from).collect(),
+            stream,
+            format: request.response_format.as_ref().map(|format| match format {
+                ResponseFormat::Json => json!("json"),
+                ResponseFormat::Schema(schema) => schema.clone()
+            }),
+            tools: openai_tools(&request.tools),
+            options: OllamaOptions {
+                num_predict: request.max_tokens,
+                temperature: request.sampling.temperature,
+                top_p: request.sampling.top_p,
+                seed: request.sampling.seed,
+                stop: &request.sampling.stop,
+                presence_penalty: request.sampling.presence_penalty,
+                frequency_penalty: request.sampling.frequency_penalty
+            }
+        }
+    }
+
+    fn send(&self, request: &ChatRequest, stream: bool) -> Result<reqwest::blocking::Response, LlmError> {
+        let body = self.payload(request, stream);
+
+        self.retry.send(|| self.client.post(&self.url).json(&body))
+    }
+}
+
+// Anthropic Messages API. The system prompt is a top level field rather than
+// a message, and only user/assistant turns are allowed in `messages`. There is
+// no JSON mode, `response_format` relies on the instructions in the prompt,
+// and no seed or presence/frequency penalties.
+
+#[derive(Serialize, Debug)]
+struct AnthropicPayload<'a> {
+    model: &'a str,
+    max_tokens: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system: Option<String>,
+    messages: Vec<AnthropicMessage<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    stop_sequences: &'a [String],
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<Value>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    stream: bool
+}
+
+#[derive(Deserialize, Debug)]
+struct AnthropicResponse {
+    content: Vec<AnthropicContent>,
+    stop_reason: Option<String>,
+    usage: Option<AnthropicUsage>
+}
+
+#[derive(Deserialize, Debug)]
+struct AnthropicUsage {
+    input_tokens: Option<u32>,
+    output_tokens: Option<u32>
+}
+
+#[derive(Deserialize, Debug)]
+struct AnthropicContent {
+    #[serde(rename = "type")]
+    kind: String,
+    text: Option<String>,
+    // Set on `tool_use` blocks
+    id: Option<String>,
+    name: Option<String>,
+    input: Option<Value>
+}
+
+#[derive(Serialize, Debug)]
+struct AnthropicMessage<'a> {
+    role: Role,
+    content: Vec<AnthropicBlock<'a>>
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum AnthropicBlock<'a> {
+    Text { text: &'a str },
+    Image { source: AnthropicImage<'a> },
+    ToolUse { id: &'a str, name: &'a str, input: &'a Value },
+    ToolResult { tool_use_id: &'a str, content: String }
+}
+
+#[derive(Serialize, Debug)]
+struct AnthropicImage<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    media_type: &'a str,
+    data: &'a str
+}
+
+/// Tool calls become `tool_use` blocks of the assistant message, and tool
+/// results `tool_result` blocks of the following user message. Results of
+/// several calls have to share that one message to keep the turns
+/// alternating.
+fn anthropic_messages(messages: &[ChatMessage]) -> Vec<AnthropicMessage<'_>> {
+    let mut wire: Vec<AnthropicMessage> = Vec::new();
+
+    for message in messages.iter().filter(|m| m.role != Role::System) {
+        if let Some(id) = &message.tool_call_id {
+            let result = AnthropicBlock::ToolResult { tool_use_id: id, content: message.text_content() };
+
+            match wire.last_mut() {
+                Some(last) if last.role == Role::User && last.content.iter().all(|b| matches!(b, AnthropicBlock::ToolResult { .. })) => {
+                    last.content.push(result)
+                }
+                _ => wire.push(AnthropicMessage { role: Role::User, content: vec![result] })
+            }
+            continue;
+        }
+
+        let mut content: Vec<AnthropicBlock> = message.content.iter()
+            .filter(|part| !matches!(part, ContentPart::Text(text) if text.is_empty()))
+            .map(|part| match part {
+                ContentPart::Text(text) => AnthropicBlock::Text { text },
+                ContentPart::Image { media_type, data } => AnthropicBlock::Image {
+                    source: AnthropicImage { kind: "base64", media_type, data }
+                }
+            })
+            .collect();
+
+        for call in &message.tool_calls {
+            content.push(AnthropicBlock::ToolUse { id: &call.id, name: &call.name, input: &call.arguments });
+        }
+
+        wire.push(AnthropicMessage { role: message.role, content });
+    }
+
+    wire
+}
+
+// Only the fields of the streaming events we act on, see
+// https://docs.anthropic.com/en/api/messages-streaming
+#[derive(Deserialize, Debug)]
+struct AnthropicEvent {
+    delta: Option<AnthropicDelta>,
+    error: Option<serde_json::Value>,
+    // message_start carries the input tokens, message_delta the output tokens
+    message: Option<AnthropicMessageStart>,
+    usage: Option<AnthropicUsage>
+}
+
+#[derive(Deserialize, Debug)]
+struct AnthropicMessageStart {
+    usage: Option<AnthropicUsage>
+}
+
+#[derive(Deserialize, Debug)]
+struct AnthropicDelta {
+    text: Option<String>,
+    stop_reason: Option<String>
+}
+
+pub struct Anthropic {
+    url: String,
+    credentials: Credentials,
+    client: reqwest::blocking::Client,
+    retry: RetryPolicy
+}
+
+impl Anthropic {
+    pub fn new(config: &ProviderConfig) -> Self {
+        let base_url = base_url(config, "ANTHROPIC_BASE_URL", "https://api.anthropic.com/v1");
+        Anthropic {
+            url: format!("{}/messages", base_url),
+            credentials: Credentials::new(config, "ANTHROPIC_API_KEY"),
+            client: reqwest::blocking::Client::new(),
+            retry: RetryPolicy::default()
+        }
+    }
+}
+
+impl ChatBackend for Anthropic {
+    fn name(&self) -> &str {
+        "anthropic"
+    }
+
+    fn endpoint(&self) -> &str {
+        &self.url
+    }
+
+    fn complete(&self, request: &ChatRequest) -> Result<Completion, LlmError> {
+        let response: AnthropicResponse = self.send(request, false)?.json()?;
+
+        let mut completion = Completion::empty();
+        completion.finish_reason = response.stop_reason;
+        completion.usage = response.usage.map(|usage| Usage {
+            prompt_tokens: usage.input_tokens.unwrap_or(0),
+            completion_tokens: usage.output_tokens.unwrap_or(0)
+        });
+
+        for block in response.content {
+            match block.kind.as_str() {
+                "text" => completion.content.push_str(&block.text.unwrap_or_default()),
+                "tool_use" => completion.tool_calls.push(ToolCall {
+                    id: block.id.unwrap_or_default(),
+                    name: block.name.unwrap_or_default(),
+                    arguments: block.input.unwrap_or_else(|| json!({}))
+                }),
+                _ => {}
+            }
+        }
+
+        Ok(completion)
+    }
+
+    fn stream(&self, request: &ChatRequest, on_delta: &mut dyn FnMut(&str)) -> Result<Completion, LlmError> {
+        let response = self.send(request, true)?;
+
+        let mut completion = Completion::empty();
+
+        for event in SseEvents::new(BufReader::new(response)) {
+            let event = event?;
+
+            match event.event.as_deref() {
+                Some("message_start") | Some("content_block_delta") | Some("message_delta") | Some("error") => {}
+                Some("message_stop") => break,
+                _ => continue
+            }
+
+            let parsed: AnthropicEvent = serde_json::from_str(&event.data)?;
+
+            if let Some(error) = parsed.error {
+                return Err(LlmError::Decode(format!("anthropic stream failed: {}", error)));
+            }
+
+            let usage = parsed.message.and_then(|message| message.usage).or(parsed.usage);
+            if let Some(usage) = usage {
+                let total = completion.usage.get_or_insert_with(Usage::default);
+                if let Some(input) = usage.input_tokens {
+                    total.prompt_tokens = input;
+                }
+                if let Some(output) = usage.output_tokens {
+                    total.completion_tokens = output;
+                }
+            }
+
+            if let Some(delta) = parsed.delta {
+                if let Some(text) = delta.text {
+                    on_delta(&text);
+                    completion.content.push_str(&text);
+                }
+                if delta.stop_reason.is_some() {
+                    completion.finish_reason = delta.stop_reason;
+                }
+            }
+        }
+
+        Ok(completion)
+    }
+
+    fn supports_prefill(&self) -> bool {
+        true
+    }
+}
+
+impl Anthropic {
+    fn payload<'a>(&self, request: &'a ChatRequest, stream: bool) -> AnthropicPayload<'a> {
+        let system: Vec<String> = request.messages.iter()
+            .filter(|m| m.role == Role::System)
+            .map(|m| m.text_content())
+            .collect();
+
+        AnthropicPayload {
+            model: &request.model,
+            max_tokens: request.max_tokens,
+            system: if system.is_empty() { None } else { Some(system.join("\n\n")) },
+            messages: anthropic_messages(&request.messages),
+            temperature: request.sampling.temperature,
+            top_p: request.sampling.top_p,
+            stop_sequences: &request.sampling.stop,
+            tools: request.tools.iter().map(|tool| json!({
+                "name": tool.name,
+                "description": tool.description,
+                "input_schema": tool.parameters
+            })).collect(),
+            stream
+        }
+    }
+
+    fn send(&self, request: &ChatRequest, stream: bool) -> Result<reqwest::blocking::Response, LlmError> {
+        let key = self.credentials.get("anthropic")?;
+        let body = self.payload(request, stream);
+
+        self.retry.send(|| {
+            self.client.post(&self.url)
+                .header("x-api-key", &key)
+                .header("anthropic-version", "2023-06-01")
+                .json(&body)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::Conversation;
+
+    fn multi_turn() -> ChatRequest {
+        let mut conversation = Conversation::default();
+        conversation
+            .system("Be brief.")
+            .user("What is EBITDA?")
+            .assistant("Earnings before interest, taxes, depreciation and amortization.")
+            .user("And EBIT?");
+        conversation.request("test-model", 256)
+    }
+
+    fn with_tool_calls() -> ChatRequest {
+        let call = |id: &str, ticker: &str| ToolCall {
+            id: id.to_string(),
+            name: "list_filings".to_string(),
+            arguments: json!({ "ticker": ticker })
+        };
+
+        let mut reply = ChatMessage::text(Role::Assistant, "");
+        reply.tool_calls = vec![call("call_1", "AAPL"), call("call_2", "MSFT")];
+
+        let mut conversation = Conversation::default();
+        conversation
+            .user("Which filings are there?")
+            .push(reply)
+            .push(ChatMessage::tool_result("call_1", "10-K.pdf"))
+            .push(ChatMessage::tool_result("call_2", "10-Q.pdf"));
+        conversation.request("test-model", 256)
+    }
+
+    // A question about a chart, the image read the way `query --file` does
+    fn with_image() -> ChatRequest {
+        let path = std::env::temp_dir().join(format!("llm_search_chart_{}.PNG", std::process::id()));
+        std::fs::write(&path, b"\x89PNG\r\n\x1a\n").unwrap();
+        let image = crate::helper::read_image(&path).unwrap().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut conversation = Conversation::default();
+        conversation.push(ChatMessage::new(Role::User, vec![ContentPart::Text("What does this chart show?".to_string()), image]));
+        conversation.request("test-model", 256)
+    }
+
+    #[test]
+    fn openai_multi_turn_payload() {
+        let backend = OpenAICompatible::openai(&ProviderConfig::default());
+        let request = multi_turn();
+
+        assert_eq!(serde_json::to_value(backend.payload(&request, true)).unwrap(), json!({
+            "messages": [
+                { "role": "system", "content": "Be brief." },
+                { "role": "user", "content": "What is EBITDA?" },
+                { "role": "assistant", "content": "Earnings before interest, taxes, depreciation and amortization." },
+                { "role": "user", "content": "And EBIT?" }
+            ],
+            "model": "test-model",
+            "max_tokens": 256,
+            "stream": true,
+            "stream_options": { "include_usage": true }
+        }));
+
+        assert!(serde_json::to_value(backend.payload(&request, false)).unwrap().get("stream_options").is_none());
+    }
+
+    #[test]
+    fn openai_stream_reads_the_usage_chunk() {
+        let body = concat!(
+            "data: {\"choices\":[{\"delta\":{\"content\":\"EBIT is \"},\"finish_reason\":null}],\"usage\":null}\n\n",
+            "data: {\"choices\":[{\"delta\":{\"content\":\"operating profit.\"},\"finish_reason\":\"stop\"}],\"usage\":null}\n\n",
+            "data: {\"choices\":[],\"usage\":{\"prompt_tokens\":31,\"completion_tokens\":5}}\n\n",
+            "data: [DONE]\n\n"
+        );
+        let mut deltas = Vec::new();
+
+        let completion = read_openai_stream(body.as_bytes(), &mut |delta| deltas.push(delta.to_string())).unwrap();
+
+        assert_eq!(deltas, vec!["EBIT is ", "operating profit."]);
+        assert_eq!(completion.content, "EBIT is operating profit.");
+        assert_eq!(completion.finish_reason.as_deref(), Some("stop"));
+        let usage = completion.usage.unwrap();
+        assert_eq!((usage.prompt_tokens, usage.completion_tokens), (31, 5));
+    }
+
+    #[test]
+    fn openai_tool_call_payload() {
+        let backend = OpenAICompatible::groq(&ProviderConfig::default());
+        let request = with_tool_calls();
+
+        assert_eq!(serde_json::to_value(backend.payload(&request, false)).unwrap()["messages"], json!([
+            { "role": "user", "content": "Which filings are there?" },
+            {
+                "role": "assistant",
+                "content": "",
+                "tool_calls": [
+                    { "id": "call_1", "type": "function", "function": { "name": "list_filings", "arguments": "{\"ticker\":\"AAPL\"}" } },
+                    { "id": "call_2", "type": "function", "function": { "name": "list_filings", "arguments": "{\"ticker\":\"MSFT\"}" } }
+                ]
+            },
+            { "role": "tool", "content": "10-K.pdf", "tool_call_id": "call_1" },
+            { "role": "tool", "content": "10-Q.pdf", "tool_call_id": "call_2" }
+        ]));
+    }
+
+    #[test]
+    fn openai_image_payload() {
+        let backend = OpenAICompatible::openai(&ProviderConfig::default());
+        let request = with_image();
+
+        assert_eq!(serde_json::to_value(backend.payload(&request, false)).unwrap()["messages"], json!([
+            {
+                "role": "user",
+                "content": [
+                    { "type": "text", "text": "What does this chart show?" },
+                    { "type": "image_url", "image_url": { "url": "data:image/png;base64,iVBORw0KGgo=" } }
+                ]
+            }
+        ]));
+    }
+
+    #[test]
+    fn ollama_multi_turn_payload() {
+        let backend = Ollama::new(&ProviderConfig::default());
+        let mut request = multi_turn();
+        request.messages[3].content.push(ContentPart::Image { media_type: "image/png".to_string(), data: "iVBORw0KGgo=".to_string() });
+
+        assert_eq!(serde_json::to_value(backend.payload(&request, false)).unwrap(), json!({
+            "model": "test-model",
+            "messages": [
+                { "role": "system", "content": "Be brief." },
+                { "role": "user", "content": "What is EBITDA?" },
+                { "role": "assistant", "content": "Earnings before interest, taxes, depreciation and amortization." },
+                { "role": "user", "content": "And EBIT?", "images": ["iVBORw0KGgo="] }
+            ],
+            "stream": false,
+            "options": { "num_predict": 256 }
+        }));
+    }
+
+    #[test]
+    fn anthropic_multi_turn_payload() {
+        let backend = Anthropic::new(&ProviderConfig::default());
+        let request = multi_turn();
+
+        assert_eq!(serde_json::to_value(backend.payload(&request, false)).unwrap(), json!({
+            "model": "test-model",
+            "max_tokens": 256,
+            "system": "Be brief.",
+            "messages": [
+                { "role": "user", "content": [{ "type": "text", "text": "What is EBITDA?" }] },
+                { "role": "assistant", "content": [{ "type": "text", "text": "Earnings before interest, taxes, depreciation and amortization." }] },
+                { "role": "user", "content": [{ "type": "text", "text": "And EBIT?" }] }
+            ]
+        }));
+    }
+
+    #[test]
+    fn anthropic_image_payload() {
+        let backend = Anthropic::new(&ProviderConfig::default());
+        let request = with_image();
+
+        assert_eq!(serde_json::to_value(backend.payload(&request, false)).unwrap()["messages"], json!([
+            {
+                "role": "user",
+                "content": [
+                    { "type": "text", "text": "What does this chart show?" },
+                    { "type": "image", "source": { "type": "base64", "media_type": "image/png", "data": "iVBORw0KGgo=" } }
+                ]
+            }
+        ]));
+    }
+
+    #[test]
+    fn anthropic_tool_results_share_one_user_turn() {
+        let backend = Anthropic::new(&ProviderConfig::default());
+        let request = with_tool_calls();
+
+        assert_eq!(serde_json::to_value(backend.payload(&request, false)).unwrap()["messages"], json!([
+            { "role": "user", "content": [{ "type": "text", "text": "Which filings are there?" }] },
+            {
+                "role": "assistant",
+                "content": [
+                    { "type": "tool_use", "id": "call_1", "name": "list_filings", "input": { "ticker": "AAPL" } },
+                    { "type": "tool_use", "id": "call_2", "name": "list_filings", "input": { "ticker": "MSFT" } }
+                ]
+            },
+            {
+                "role": "user",
+                "content": [
+                    { "type": "tool_result", "tool_use_id": "call_1", "content": "10-K.pdf" },
+                    { "type": "tool_result", "tool_use_id": "call_2", "content": "10-Q.pdf" }
+                ]
+            }
+        ]));
+    }
+
+    #[test]
+    fn sampling_reaches_every_provider() {
+        let mut request = multi_turn();
+        request.sampling = Sampling {
+            temperature: Some(0.5),
+            top_p: None,
+            seed: Some(7),
+            stop: vec!["END".to_string()],
+            presence_penalty: None,
+            frequency_penalty: Some(0.5)
+        };
+
+        let openai = serde_json::to_value(OpenAICompatible::openai(&ProviderConfig::default()).payload(&request, false)).unwrap();
+        assert_eq!(openai["temperature"], json!(0.5));
+        assert_eq!(openai["seed"], json!(7));
+        assert_eq!(openai["stop"], json!(["END"]));
+        assert_eq!(openai["frequency_penalty"], json!(0.5));
+        assert!(openai.get("top_p").is_none());
+
+        let ollama = serde_json::to_value(Ollama::new(&ProviderConfig::default()).payload(&request, false)).unwrap();
+        assert_eq!(ollama["options"], json!({ "num_predict": 256, "temperature": 0.5, "seed": 7, "stop": ["END"], "frequency_penalty": 0.5 }));
+
+        let anthropic = serde_json::to_value(Anthropic::new(&ProviderConfig::default()).payload(&request, false)).unwrap();
+        assert_eq!(anthropic["temperature"], json!(0.5));
+        assert_eq!(anthropic["stop_sequences"], json!(["END"]));
+        assert!(anthropic.get("seed").is_none());
+    }
+}
@@ -0,0 +1,288 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use indicatif::{ProgressBar, ProgressStyle};
+use serde::{Deserialize, Serialize};
+
+use crate::backend::Usage;
+use crate::config::Config;
+use crate::llm::LLM;
+use crate::models::{ModelInfo, ModelRegistry};
+use crate::usage::UsageMeter;
+use crate::{build_llm, GenericError};
+
+/// One line of the input file. Rows without an `id` are named after their
+/// line number, so reruns can still tell which ones are done.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct BatchRequest {
+    id: Option<String>,
+    prompt: String,
+    system: Option<String>,
+    model: Option<String>
+}
+
+/// One line of the output file.
+#[derive(Serialize, Deserialize, Debug)]
+struct BatchResult {
+    id: String,
+    model: Option<String>,
+    output: Option<String>,
+    usage: Option<Usage>,
+    error: Option<String>
+}
+
+pub struct Batch<'a> {
+    pub config: &'a Config,
+    pub registry: &'a ModelRegistry,
+    pub model: Option<String>,
+    pub system: Option<String>,
+    pub concurrency: usize,
+    /// Shared by the clients of every model the batch uses.
+    pub meter: Arc<UsageMeter>
+}
+
+impl Batch<'_> {
+    /// Runs every request in `input` that doesn't already have a successful
+    /// result in `output`, appending results as they complete. Failed rows
+    /// are written with an `error` and retried on the next run. Once the run
+    /// is over `output` keeps only the latest row for each id.
+    pub fn run(&self, input: &Path, output: &Path) -> Result<(), GenericError> {
+        let requests = read_requests(input)?;
+        let done = completed_ids(output)?;
+
+        let pending: Vec<(String, BatchRequest)> = requests.into_iter()
+            .filter(|(id, _)| !done.contains(id))
+            .collect();
+
+        if pending.is_empty() {
+            compact(output)?;
+            println!("All {} requests already have results in {}", done.len(), output.display());
+            return Ok(());
+        }
+
+        println!("{} requests to run, {} already done", pending.len(), done.len());
+
+        // Build one client per model up front so workers only clone them
+        let mut llms: HashMap<Option<String>, Result<(LLM, ModelInfo), String>> = HashMap::new();
+        for (_, request) in &pending {
+            let name = request.model.clone().or(self.model.clone());
+            llms.entry(name.clone()).or_insert_with(|| {
+                build_llm(self.config, self.registry, name.as_ref(), "L8")
+                    .map(|(mut llm, model)| {
+                        llm.meter = self.meter.clone();
+                        (llm, model)
+                    })
+                    .map_err(|e| e.to_string())
+            });
+        }
+
+        let mut file = OpenOptions::new().create(true).append(true).open(output)?;
+
+        let progress = ProgressBar::new(pending.len() as u64);
+        progress.set_style(ProgressStyle::with_template("{bar:40} {pos}/{len} [{elapsed_precise}] {msg}")?);
+
+        let total = pending.len();
+        let queue = Mutex::new(pending.into_iter());
+        let (sender, receiver) = mpsc::channel::<BatchResult>();
+        let mut failed = 0;
+
+        thread::scope(|scope| -> Result<(), GenericError> {
+            for _ in 0..self.concurrency.clamp(1, total) {
+                let sender = sender.clone();
+                let queue = &queue;
+                let llms = &llms;
+                let system = &self.system;
+
+                scope.spawn(move || {
+                    loop {
+                        let next = queue.lock().unwrap().next();
+                        let Some((id, request)) = next else { break };
+
+                        let name = request.model.clone().or(self.model.clone());
+                        let result = match &llms[&name] {
+                            Ok((llm, model)) => run_request(llm, model, id, request, system),
+                            Err(e) => BatchResult { id, model: name, output: None, usage: None, error: Some(e.clone()) }
+                        };
+
+                        if sender.send(result).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+            drop(sender);
+
+            for result in receiver {
+                if result.error.is_some() {
+                    failed += 1;
+                    progress.set_message(format!("{} failed", failed));
+                }
+                writeln!(file, "{}", serde_json::to_string(&result)?)?;
+                file.flush()?;
+                progress.inc(1);
+            }
+
+            Ok(())
+        })?;
+
+        progress.finish();
+        compact(output)?;
+        println!("{} succeeded, {} failed, results in {}", total - failed, failed, output.display());
+
+        Ok(())
+    }
+}
+
+fn run_request(llm: &LLM, model: &ModelInfo, id: String, request: BatchRequest, system: &Option<String>) -> BatchResult {
+    let mut llm = llm.clone();
+    if let Some(system) = request.system.as_ref().or(system.as_ref()) {
+        llm.system = Some(system.clone());
+    }
+
+    let mut result = BatchResult { id, model: Some(model.id.clone()), output: None, usage: None, error: None };
+
+    match llm.complete(Some(request.prompt), model) {
+        Ok(completion) => {
+            if completion.truncated() {
+                result.error = Some("stopped at max_tokens, the output is incomplete".to_string());
+            } else if completion.content.trim().is_empty() {
+                result.error = Some("empty response".to_string());
+            }
+            result.output = Some(completion.content);
+            result.usage = completion.usage;
+        }
+        Err(e) => result.error = Some(e.to_string())
+    }
+
+    result
+}
+
+fn read_requests(input: &Path) -> Result<Vec<(String, BatchRequest)>, GenericError> {
+    let file = std::fs::File::open(input)
+        .map_err(|e| format!("could not read {}: {}", input.display(), e))?;
+
+    let mut requests = Vec::new();
+    let mut ids = HashSet::new();
+
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let request: BatchRequest = serde_json::from_str(&line)
+            .map_err(|e| format!("{} line {}: {}", input.display(), number + 1, e))?;
+        let id = request.id.clone().unwrap_or_else(|| format!("line-{}", number + 1));
+
+        if !ids.insert(id.clone()) {
+            return Err(format!("{} line {}: duplicate id {}", input.display(), number + 1, id).into());
+        }
+
+        requests.push((id, request));
+    }
+
+    Ok(requests)
+}
+
+/// IDs that already have a result without an error.
+fn completed_ids(output: &Path) -> Result<HashSet<String>, GenericError> {
+    let mut done = HashSet::new();
+
+    if !output.exists() {
+        return Ok(done);
+    }
+
+    for line in BufReader::new(std::fs::File::open(output)?).lines() {
+        // A line cut short by an interrupted run is simply redone
+        if let Ok(result) = serde_json::from_str::<BatchResult>(&line?) {
+            if result.error.is_none() {
+                done.insert(result.id);
+            }
+        }
+    }
+
+    Ok(done)
+}
+
+/// Rewrites `output` with only the latest row for each id, so the errors of
+/// rows that were retried don't linger next to their result.
+fn compact(output: &Path) -> Result<(), GenericError> {
+    if !output.exists() {
+        return Ok(());
+    }
+
+    let mut results: Vec<BatchResult> = Vec::new();
+    for line in BufReader::new(std::fs::File::open(output)?).lines() {
+        if let Ok(result) = serde_json::from_str::<BatchResult>(&line?) {
+            results.push(result);
+        }
+    }
+
+    let mut latest = HashMap::new();
+    for (i, result) in results.iter().enumerate() {
+        latest.insert(result.id.clone(), i);
+    }
+
+    let mut text = String::new();
+    for (i, result) in results.iter().enumerate() {
+        if latest[&result.id] == i {
+            text.push_str(&serde_json::to_string(result)?);
+            text.push('\n');
+        }
+    }
+
+    let temporary = output.with_extension("jsonl.tmp");
+    std::fs::write(&temporary, text)?;
+    std::fs::rename(&temporary, output)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(id: &str, error: Option<&str>) -> String {
+        let result = BatchResult {
+            id: id.to_string(),
+            model: Some("test-model".to_string()),
+            output: error.is_none().then(|| format!("answer {}", id)),
+            usage: None,
+            error: error.map(str::to_string)
+        };
+        serde_json::to_string(&result).unwrap()
+    }
+
+    #[test]
+    fn rerun_after_an_error_keeps_one_row_per_id() {
+        let path = std::env::temp_dir().join(format!("llm_search_batch_{}.jsonl", std::process::id()));
+
+        // A first run where q2 failed and a second where its retry failed
+        // again, then a third that got an answer and was cut off mid-line
+        let lines = [
+            result("q1", None),
+            result("q2", Some("provider returned 500")),
+            result("q3", None),
+            result("q2", Some("provider returned 503")),
+            result("q2", None),
+            "{\"id\":\"q4\",\"mod".to_string()
+        ];
+        std::fs::write(&path, lines.join("\n")).unwrap();
+
+        assert_eq!(completed_ids(&path).unwrap(), HashSet::from(["q1".to_string(), "q2".to_string(), "q3".to_string()]));
+
+        compact(&path).unwrap();
+
+        let rows: Vec<BatchResult> = std::fs::read_to_string(&path).unwrap().lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let ids: Vec<&str> = rows.iter().map(|row| row.id.as_str()).collect();
+        assert_eq!(ids, vec!["q1", "q3", "q2"]);
+        assert!(rows.iter().all(|row| row.error.is_none()));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,171 @@
+use std::path::PathBuf;
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::backend::{ChatRequest, Completion};
+use crate::GenericError;
+
+#[derive(Serialize, Deserialize)]
+struct CacheEntry {
+    created_at: DateTime<Utc>,
+    completion: Completion
+}
+
+/// Replies saved on disk by request, so sending the exact same request again
+/// within `ttl` costs nothing. One JSON file per entry, named by its key.
+#[derive(Clone, Debug)]
+pub struct ResponseCache {
+    dir: PathBuf,
+    ttl: Duration
+}
+
+impl ResponseCache {
+    pub fn new(dir: PathBuf, ttl: Duration) -> Self {
+        ResponseCache { dir, ttl }
+    }
+
+    pub fn get(&self, key: &str) -> Option<Completion> {
+        let text = std::fs::read_to_string(self.path(key)).ok()?;
+        let entry: CacheEntry = serde_json::from_str(&text).ok()?;
+
+        if self.expired(&entry) {
+            return None;
+        }
+
+        Some(entry.completion)
+    }
+
+    /// Saves `completion` under `key`. A cache that can't be written only
+    /// costs money later, so failures are a warning.
+    pub fn put(&self, key: &str, completion: &Completion) {
+        let entry = CacheEntry { created_at: Utc::now(), completion: completion.clone() };
+
+        let result = std::fs::create_dir_all(&self.dir)
+            .map_err(GenericError::from)
+            .and_then(|_| Ok(serde_json::to_string(&entry)?))
+            .and_then(|json| Ok(std::fs::write(self.path(key), json)?));
+
+        if let Err(e) = result {
+            eprintln!("WARNING: could not write to the response cache {}: {}", self.dir.display(), e);
+        }
+    }
+
+    /// Deletes every entry, or with `expired_only` just the ones past their
+    /// TTL. Returns how many were deleted.
+    pub fn clear(&self, expired_only: bool) -> Result<usize, GenericError> {
+        if !self.dir.exists() {
+            return Ok(0);
+        }
+
+        let mut removed = 0;
+
+        for entry in std::fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+
+            if expired_only {
+                // Unreadable entries are as good as expired
+                let keep = std::fs::read_to_string(&path).ok()
+                    .and_then(|text| serde_json::from_str::<CacheEntry>(&text).ok())
+                    .is_some_and(|entry| !self.expired(&entry));
+                if keep {
+                    continue;
+                }
+            }
+
+            std::fs::remove_file(&path)?;
+            removed += 1;
+        }
+
+        Ok(removed)
+    }
+
+    fn expired(&self, entry: &CacheEntry) -> bool {
+        Utc::now().signed_duration_since(entry.created_at).to_std().is_ok_and(|age| age > self.ttl)
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", key))
+    }
+}
+
+/// SHA-256 of everything that decides the reply to `request` on `provider`
+/// at `endpoint`: the model, messages, sampling, output limit, response
+/// format and tools. `None` for a request that can't be serialized, which is
+/// then not cached at all.
+pub fn key(provider: &str, endpoint: &str, request: &ChatRequest) -> Option<String> {
+    let request = serde_json::to_vec(request).ok()?;
+
+    let mut hasher = Sha256::new();
+    hasher.update(provider.as_bytes());
+    hasher.update([0]);
+    hasher.update(endpoint.as_bytes());
+    hasher.update([0]);
+    hasher.update(request);
+    Some(format!("{:x}", hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::Conversation;
+
+    fn request(question: &str) -> ChatRequest {
+        let mut conversation = Conversation::default();
+        conversation.system("Be brief.").user(question);
+        conversation.request("test-model", 256)
+    }
+
+    #[test]
+    fn key_changes_with_anything_that_changes_the_reply() {
+        const OPENAI: &str = "https://api.openai.com/v1/chat/completions";
+        let key = |provider, endpoint, request: &ChatRequest| key(provider, endpoint, request).unwrap();
+        let base = key("openai", OPENAI, &request("What is EBITDA?"));
+
+        assert_eq!(base, key("openai", OPENAI, &request("What is EBITDA?")));
+        assert_ne!(base, key("groq", OPENAI, &request("What is EBITDA?")));
+        assert_ne!(base, key("openai", "https://gateway.example.com/v1/chat/completions", &request("What is EBITDA?")));
+        assert_ne!(base, key("openai", OPENAI, &request("What is EBIT?")));
+
+        let mut warmer = request("What is EBITDA?");
+        warmer.sampling.temperature = Some(0.9);
+        assert_ne!(base, key("openai", OPENAI, &warmer));
+
+        let mut longer = request("What is EBITDA?");
+        longer.max_tokens = 512;
+        assert_ne!(base, key("openai", OPENAI, &longer));
+    }
+
+    #[test]
+    fn entries_expire_and_can_be_cleared() {
+        let dir = std::env::temp_dir().join(format!("llm_search_cache_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let completion = Completion {
+            content: "Earnings before interest.".to_string(),
+            finish_reason: Some("stop".to_string()),
+            usage: None,
+            tool_calls: Vec::new()
+        };
+
+        let cache = ResponseCache::new(dir.clone(), Duration::from_secs(3600));
+        cache.put("fresh", &completion);
+        assert_eq!(cache.get("fresh").map(|c| c.content), Some(completion.content.clone()));
+        assert!(cache.get("missing").is_none());
+
+        let stale = CacheEntry { created_at: Utc::now() - chrono::Duration::hours(2), completion };
+        std::fs::write(cache.path("stale"), serde_json::to_string(&stale).unwrap()).unwrap();
+        assert!(cache.get("stale").is_none());
+
+        assert_eq!(cache.clear(true).unwrap(), 1);
+        assert!(cache.get("fresh").is_some());
+        assert_eq!(cache.clear(false).unwrap(), 1);
+        assert!(cache.get("fresh").is_none());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
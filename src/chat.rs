@@ -0,0 +1,215 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::backend::{ChatRequest, Sampling};
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+    Tool
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub enum ContentPart {
+    Text(String),
+    /// Base64 encoded image with its MIME type, e.g. `image/png`.
+    Image { media_type: String, data: String }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    pub arguments: Value
+}
+
+/// One message of a conversation. Each backend maps these onto its
+/// provider's own wire format.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct ChatMessage {
+    pub role: Role,
+    pub content: Vec<ContentPart>,
+    /// Tells apart participants sharing a role, where the provider supports it.
+    pub name: Option<String>,
+    /// Tools the assistant asked to have run.
+    pub tool_calls: Vec<ToolCall>,
+    /// The call a `Tool` message holds the result of.
+    pub tool_call_id: Option<String>
+}
+
+impl ChatMessage {
+    pub fn new(role: Role, content: Vec<ContentPart>) -> Self {
+        ChatMessage { role, content, name: None, tool_calls: Vec::new(), tool_call_id: None }
+    }
+
+    pub fn text(role: Role, text: &str) -> Self {
+        Self::new(role, vec![ContentPart::Text(text.to_string())])
+    }
+
+    pub fn tool_result(call_id: &str, result: &str) -> Self {
+        let mut message = Self::text(Role::Tool, result);
+        message.tool_call_id = Some(call_id.to_string());
+        message
+    }
+
+    /// Whether there is nothing to send, like the empty assistant turn a
+    /// failed reply leaves behind. Tool results always count, even when the
+    /// tool had nothing to say.
+    pub fn is_empty(&self) -> bool {
+        self.role != Role::Tool
+            && self.tool_calls.is_empty()
+            && self.content.iter().all(|part| matches!(part, ContentPart::Text(text) if text.trim().is_empty()))
+    }
+
+    /// Folds `other` into this message, for two turns in a row by the same
+    /// role.
+    fn append(&mut self, other: ChatMessage) {
+        for part in other.content {
+            match (self.content.last_mut(), part) {
+                (Some(ContentPart::Text(text)), ContentPart::Text(more)) => {
+                    text.push_str("\n\n");
+                    text.push_str(&more);
+                }
+                (_, part) => self.content.push(part)
+            }
+        }
+        self.tool_calls.extend(other.tool_calls);
+    }
+
+    /// All text parts joined together, images left out.
+    pub fn text_content(&self) -> String {
+        self.content.iter()
+            .filter_map(|part| match part {
+                ContentPart::Text(text) => Some(text.as_str()),
+                ContentPart::Image { .. } => None
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// Builds the messages of a request, the one way every caller puts a prompt
+/// together. Whatever order messages are added in, the result is one
+/// providers accept: system messages first, then user and assistant turns
+/// taking turns, with tool results right after the call that asked for them.
+#[derive(Default, Clone, Debug)]
+pub struct Conversation {
+    messages: Vec<ChatMessage>
+}
+
+impl Conversation {
+    pub fn system(&mut self, text: &str) -> &mut Self {
+        self.push(ChatMessage::text(Role::System, text))
+    }
+
+    pub fn user(&mut self, text: &str) -> &mut Self {
+        self.push(ChatMessage::text(Role::User, text))
+    }
+
+    pub fn assistant(&mut self, text: &str) -> &mut Self {
+        self.push(ChatMessage::text(Role::Assistant, text))
+    }
+
+    /// Adds `message`. Empty messages are dropped, a system message joins the
+    /// ones at the start, and a user or assistant message following one of
+    /// the same role is merged into it.
+    pub fn push(&mut self, message: ChatMessage) -> &mut Self {
+        if message.is_empty() {
+            return self;
+        }
+
+        if message.role == Role::System {
+            let at = self.messages.iter().take_while(|m| m.role == Role::System).count();
+            self.messages.insert(at, message);
+            return self;
+        }
+
+        match self.messages.last_mut() {
+            Some(last) if last.role == message.role && last.role != Role::Tool && last.name == message.name => {
+                last.append(message)
+            }
+            _ => self.messages.push(message)
+        }
+
+        self
+    }
+
+    pub fn messages(&self) -> &[ChatMessage] {
+        &self.messages
+    }
+
+    pub fn request(&self, model: &str, max_tokens: i32) -> ChatRequest {
+        ChatRequest {
+            model: model.to_string(),
+            messages: self.messages.clone(),
+            max_tokens,
+            sampling: Sampling::default(),
+            response_format: None,
+            tools: Vec::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roles(conversation: &Conversation) -> Vec<(Role, String)> {
+        conversation.messages().iter().map(|m| (m.role, m.text_content())).collect()
+    }
+
+    #[test]
+    fn keeps_system_messages_first() {
+        let mut conversation = Conversation::default();
+        conversation.system("be brief").user("hi").system("summary").assistant("hello");
+
+        assert_eq!(roles(&conversation), vec![
+            (Role::System, "be brief".to_string()),
+            (Role::System, "summary".to_string()),
+            (Role::User, "hi".to_string()),
+            (Role::Assistant, "hello".to_string())
+        ]);
+    }
+
+    #[test]
+    fn drops_empty_messages() {
+        let mut conversation = Conversation::default();
+        conversation.assistant("").user("first question").assistant("  ").user("second question");
+
+        assert_eq!(roles(&conversation), vec![(Role::User, "first question\n\nsecond question".to_string())]);
+    }
+
+    #[test]
+    fn merges_consecutive_turns_of_the_same_role() {
+        let mut conversation = Conversation::default();
+        conversation.user("a").user("b").assistant("c").assistant("d").user("e");
+
+        assert_eq!(roles(&conversation), vec![
+            (Role::User, "a\n\nb".to_string()),
+            (Role::Assistant, "c\n\nd".to_string()),
+            (Role::User, "e".to_string())
+        ]);
+    }
+
+    #[test]
+    fn keeps_every_tool_result() {
+        let call = |id: &str| ToolCall { id: id.to_string(), name: "list_filings".to_string(), arguments: serde_json::json!({}) };
+
+        let mut reply = ChatMessage::text(Role::Assistant, "");
+        reply.tool_calls = vec![call("1"), call("2")];
+
+        let mut conversation = Conversation::default();
+        conversation.user("q").push(reply)
+            .push(ChatMessage::tool_result("1", "AAPL/"))
+            .push(ChatMessage::tool_result("2", ""));
+
+        let messages = conversation.messages();
+        assert_eq!(messages.len(), 4);
+        assert_eq!(messages[1].tool_calls.len(), 2);
+        assert_eq!(messages[2].tool_call_id.as_deref(), Some("1"));
+        assert_eq!(messages[3].tool_call_id.as_deref(), Some("2"));
+    }
+}
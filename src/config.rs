@@ -0,0 +1,268 @@
+use std::{collections::HashMap, env, path::PathBuf, time::Duration};
+use serde::Deserialize;
+
+use crate::cache::ResponseCache;
+use crate::llm::Decoration;
+use crate::models::ModelInfo;
+use crate::valuation::ValuationConfig;
+use crate::GenericError;
+
+/// Settings read from `config.toml`. Every field is optional, anything left
+/// out falls back to an environment variable and then to a built-in default.
+///
+/// ```toml
+/// data_root = "~/Documents/financials"
+/// model = "L70"
+/// max_tokens = 8192
+/// system_prompt = "I want concise answers."
+///
+/// [decoration]
+/// suffix = "Keep the answer short."
+///
+/// [cache]
+/// ttl_hours = 24
+///
+/// [valuation]
+/// wacc = 0.09
+/// terminal_growth = 0.025
+///
+/// [providers.openai]
+/// base_url = "https://api.openai.com/v1"
+/// api_key = "sk-..."
+///
+/// [[models]]
+/// id = "llama3.1:70b"
+/// provider = "ollama"
+/// context_window = 131072
+/// max_output_tokens = 4096
+/// aliases = ["local"]
+/// input_price = 0.0   # USD per million tokens
+/// output_price = 0.0
+/// ```
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub data_root: Option<String>,
+    pub model: Option<String>,
+    pub max_tokens: Option<i32>,
+    pub system_prompt: Option<String>,
+    pub decoration: Decoration,
+    pub cache: CacheConfig,
+    pub valuation: ValuationConfig,
+    pub providers: HashMap<String, ProviderConfig>,
+    pub models: Vec<ModelInfo>
+}
+
+#[derive(Deserialize, Default, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProviderConfig {
+    pub base_url: Option<String>,
+    pub api_key: Option<String>,
+    /// Environment variable holding the key, for when the default one
+    /// (e.g. `GROQ_API_KEY`) is already used for something else.
+    pub api_key_env: Option<String>
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    pub enabled: bool,
+    /// How long a cached reply is reused for.
+    pub ttl_hours: u64
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig { enabled: true, ttl_hours: 24 * 7 }
+    }
+}
+
+const DEFAULT_MAX_TOKENS: i32 = 8192;
+
+impl Config {
+    /// Loads the config from `path` if given, otherwise from
+    /// `$LLM_SEARCH_CONFIG` or `$XDG_CONFIG_HOME/llm_search/config.toml`.
+    /// Only an explicitly requested file has to exist.
+    pub fn load(path: Option<&PathBuf>) -> Result<Config, GenericError> {
+        let explicit = path.cloned().or_else(|| env::var_os("LLM_SEARCH_CONFIG").map(PathBuf::from));
+
+        let mut config = match explicit {
+            Some(path) => Self::read(&path)?,
+            None => match config_dir().map(|dir| dir.join("config.toml")) {
+                Some(path) if path.exists() => Self::read(&path)?,
+                _ => Config::default()
+            }
+        };
+
+        config.apply_env()?;
+
+        Ok(config)
+    }
+
+    fn read(path: &PathBuf) -> Result<Config, GenericError> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("could not read config {}: {}", path.display(), e))?;
+
+        toml::from_str(&text)
+            .map_err(|e| format!("invalid config {}: {}", path.display(), e).into())
+    }
+
+    fn apply_env(&mut self) -> Result<(), GenericError> {
+        self.apply_vars(|name| env::var(name).ok())
+    }
+
+    fn apply_vars(&mut self, var: impl Fn(&str) -> Option<String>) -> Result<(), GenericError> {
+        if let Some(root) = var("LLM_SEARCH_DATA_ROOT") {
+            self.data_root = Some(root);
+        }
+        if let Some(model) = var("LLM_SEARCH_MODEL") {
+            self.model = Some(model);
+        }
+        if let Some(max_tokens) = var("LLM_SEARCH_MAX_TOKENS") {
+            self.max_tokens = Some(max_tokens.parse()
+                .map_err(|_| format!("LLM_SEARCH_MAX_TOKENS is not a number: {}", max_tokens))?);
+        }
+        if let Some(system) = var("LLM_SEARCH_SYSTEM_PROMPT") {
+            self.system_prompt = Some(system);
+        }
+
+        Ok(())
+    }
+
+    /// Folder holding one sub folder per ticker.
+    pub fn data_root(&self) -> String {
+        let root = match &self.data_root {
+            Some(root) => expand_home(root, home_dir()),
+            None => home_dir()
+                .map(|home| home.join("Documents").join("financials").to_string_lossy().to_string())
+                .unwrap_or_else(|| "financials".to_string())
+        };
+
+        root.trim_end_matches('/').to_string()
+    }
+
+    pub fn ticker_dir(&self, ticker: &str) -> String {
+        format!("{}/{}", self.data_root(), ticker)
+    }
+
+    /// Where the tool keeps its own state such as saved conversations,
+    /// `$XDG_DATA_HOME/llm_search` by default.
+    pub fn state_dir(&self) -> PathBuf {
+        env::var_os("XDG_DATA_HOME")
+            .map(PathBuf::from)
+            .or_else(|| home_dir().map(|home| home.join(".local").join("share")))
+            .unwrap_or_else(|| PathBuf::from("."))
+            .join("llm_search")
+    }
+
+    /// Replies already paid for, under `$XDG_CACHE_HOME/llm_search/responses`.
+    pub fn response_cache(&self) -> ResponseCache {
+        let dir = env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| home_dir().map(|home| home.join(".cache")))
+            .unwrap_or_else(|| PathBuf::from("."))
+            .join("llm_search")
+            .join("responses");
+
+        ResponseCache::new(dir, Duration::from_secs(self.cache.ttl_hours * 3600))
+    }
+
+    /// JSONL file every model call is logged to, for `usage`.
+    pub fn usage_ledger(&self) -> PathBuf {
+        self.state_dir().join("usage.jsonl")
+    }
+
+    pub fn max_tokens(&self) -> i32 {
+        self.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS)
+    }
+
+    pub fn provider(&self, name: &str) -> ProviderConfig {
+        self.providers.get(name).cloned().unwrap_or_default()
+    }
+}
+
+fn home_dir() -> Option<PathBuf> {
+    env::var_os("HOME").map(PathBuf::from)
+}
+
+fn config_dir() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| home_dir().map(|home| home.join(".config")))
+        .map(|dir| dir.join("llm_search"))
+}
+
+fn expand_home(path: &str, home: Option<PathBuf>) -> String {
+    match (path.strip_prefix("~/"), home) {
+        (Some(rest), Some(home)) => home.join(rest).to_string_lossy().to_string(),
+        _ => path.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<Config, toml::de::Error> {
+        toml::from_str(text)
+    }
+
+    #[test]
+    fn reads_toml_and_rejects_unknown_keys() {
+        let config = parse(r#"
+            data_root = "/data/financials/"
+            model = "sonnet"
+
+            [cache]
+            ttl_hours = 24
+
+            [providers.openai]
+            base_url = "https://gateway.example.com/v1"
+        "#).unwrap();
+
+        assert_eq!(config.model.as_deref(), Some("sonnet"));
+        assert_eq!(config.data_root(), "/data/financials");
+        assert_eq!(config.max_tokens(), DEFAULT_MAX_TOKENS);
+        assert!(config.cache.enabled);
+        assert_eq!(config.cache.ttl_hours, 24);
+        assert_eq!(config.provider("openai").base_url.as_deref(), Some("https://gateway.example.com/v1"));
+        assert!(config.provider("groq").base_url.is_none());
+
+        assert!(parse("modle = \"sonnet\"").is_err());
+        assert!(parse("[providers.openai]\nbase = \"x\"").is_err());
+        assert!(parse("[cache]\nttl = 1").is_err());
+    }
+
+    #[test]
+    fn environment_overrides_the_file() {
+        let mut config = parse("model = \"L70\"\nmax_tokens = 1000\nsystem_prompt = \"file\"").unwrap();
+        let vars = HashMap::from([("LLM_SEARCH_MODEL", "G9"), ("LLM_SEARCH_MAX_TOKENS", "2000")]);
+
+        config.apply_vars(|name| vars.get(name).map(|value| value.to_string())).unwrap();
+
+        assert_eq!(config.model.as_deref(), Some("G9"));
+        assert_eq!(config.max_tokens, Some(2000));
+        assert_eq!(config.system_prompt.as_deref(), Some("file"));
+
+        let error = config.apply_vars(|name| (name == "LLM_SEARCH_MAX_TOKENS").then(|| "lots".to_string())).unwrap_err();
+        assert!(error.to_string().contains("LLM_SEARCH_MAX_TOKENS"));
+    }
+
+    #[test]
+    fn expands_the_home_directory() {
+        let home = Some(PathBuf::from("/home/ana"));
+
+        assert_eq!(expand_home("~/financials", home.clone()), "/home/ana/financials");
+        assert_eq!(expand_home("/srv/~/financials", home.clone()), "/srv/~/financials");
+        assert_eq!(expand_home("~other/financials", home), "~other/financials");
+        assert_eq!(expand_home("~/financials", None), "~/financials");
+    }
+
+    #[test]
+    fn explicit_config_must_exist() {
+        let missing = std::env::temp_dir().join(format!("llm_search_no_config_{}.toml", std::process::id()));
+
+        let error = Config::load(Some(&missing)).unwrap_err();
+        assert!(error.to_string().contains("could not read config"));
+    }
+}
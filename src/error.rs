@@ -0,0 +1,98 @@
+use std::fmt;
+
+/// Everything that can go wrong talking to a provider. Each variant maps to
+/// its own exit code so scripts wrapping the binary can tell them apart.
+#[derive(Debug)]
+pub enum LlmError {
+    MissingCredentials { provider: String, var: String },
+    Http { status: u16, body: String },
+    Network(reqwest::Error),
+    Io(std::io::Error),
+    EmptyResponse { provider: String },
+    Truncated { provider: String, partial: String },
+    Decode(String),
+    UnknownModel(String),
+    /// A structured reply that still didn't match its schema after re-prompting.
+    InvalidOutput(String),
+    /// The model was still calling tools after this many rounds.
+    ToolLoop(usize)
+}
+
+impl LlmError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            LlmError::MissingCredentials { .. } => 3,
+            LlmError::Http { .. } => 4,
+            LlmError::Network(_) | LlmError::Io(_) => 5,
+            LlmError::EmptyResponse { .. } => 6,
+            LlmError::Truncated { .. } => 7,
+            LlmError::Decode(_) => 8,
+            LlmError::UnknownModel(_) => 9,
+            LlmError::InvalidOutput(_) => 10,
+            LlmError::ToolLoop(_) => 11
+        }
+    }
+
+    /// Builds an `Http` error, pulling the human readable message out of the
+    /// provider's JSON error body when there is one.
+    pub fn http(status: u16, body: String) -> Self {
+        let message = serde_json::from_str::<serde_json::Value>(&body).ok()
+            .and_then(|value| {
+                value.pointer("/error/message")
+                    .or_else(|| value.get("error"))
+                    .and_then(|message| message.as_str())
+                    .map(|message| message.to_string())
+            });
+
+        LlmError::Http { status, body: message.unwrap_or_else(|| body.trim().to_string()) }
+    }
+}
+
+impl fmt::Display for LlmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LlmError::MissingCredentials { provider, var } => write!(f, "no API key for {}, set {}", provider, var),
+            LlmError::Http { status, body } => write!(f, "provider returned HTTP {}: {}", status, body),
+            LlmError::Network(e) => write!(f, "could not reach provider: {}", e),
+            LlmError::Io(e) => write!(f, "could not read response: {}", e),
+            LlmError::EmptyResponse { provider } => write!(f, "{} returned an empty response", provider),
+            LlmError::Truncated { provider, .. } => write!(f, "{} stopped at max_tokens, the response is incomplete", provider),
+            LlmError::Decode(e) => write!(f, "could not decode response: {}", e),
+            LlmError::UnknownModel(name) => write!(f, "unknown model '{}', run `llm_search models` to list the available ones", name),
+            LlmError::InvalidOutput(e) => write!(f, "response did not match the expected format: {}", e),
+            LlmError::ToolLoop(rounds) => write!(f, "no answer after {} rounds of tool calls", rounds)
+        }
+    }
+}
+
+impl std::error::Error for LlmError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LlmError::Network(e) => Some(e),
+            LlmError::Io(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<reqwest::Error> for LlmError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_decode() {
+            LlmError::Decode(e.to_string())
+        } else {
+            LlmError::Network(e)
+        }
+    }
+}
+
+impl From<serde_json::Error> for LlmError {
+    fn from(e: serde_json::Error) -> Self {
+        LlmError::Decode(e.to_string())
+    }
+}
+
+impl From<std::io::Error> for LlmError {
+    fn from(e: std::io::Error) -> Self {
+        LlmError::Io(e)
+    }
+}
@@ -1,21 +1,174 @@
-use crate::{helper::{ToDocument, ToString}, llm::{Model, LLM}, GenericError};
-use std::{io::{self, Write}, path::Path, thread::sleep, time::Duration};
-use std::fs::File;
-use std::io::Read;
+use crate::{error::LlmError, helper::{pdf_pages, ToDocument}, llm::LLM, manifest::{Manifest, Stage}, models::ModelInfo, ratios, statements::{self, FinancialStatement, StatementKind}, summary::Summarizer, valuation::{self, ValuationConfig}, GenericError};
+use std::{io::{self, Write}, path::Path};
+use serde_json::json;
 
 
+// Largest chunk of report pages summarized at once, even for models with
+// room for more, so the summaries stay detailed.
+const MAX_CHUNK_TOKENS: usize = 12_000;
+
 #[derive(Clone)]
 pub struct Finance{
     ticker: String,
-    llm : LLM
+    llm : LLM,
+    model: ModelInfo,
+    data_root: String,
+    /// Ratios worked out from the statements, for the prompts to rely on.
+    figures: Option<String>,
+    pub valuation: ValuationConfig
 }
 
 impl Finance{
-    pub fn new(ticker: String, llm: LLM) -> Self{
-        Finance{ticker, llm}
+    pub fn new(ticker: String, llm: LLM, model: ModelInfo, data_root: String) -> Self{
+        Finance{ticker, llm, model, data_root, figures: None, valuation: ValuationConfig::default()}
+    }
+
+    // A report that ran into max_tokens is still worth keeping, so hold on to
+    // what came back instead of failing the whole run.
+    fn ask(&self, prompt: &str, output: bool) -> Result<String, LlmError> {
+        match self.llm.prompt(Some(prompt.trim().to_string()), &self.model, output) {
+            Err(LlmError::Truncated { provider, partial }) => {
+                eprintln!("WARNING: {} stopped at max_tokens, keeping the partial response", provider);
+                Ok(partial)
+            }
+            result => result
+        }
+    }
+
+    /// The verified figures as an instruction to append to a prompt.
+    fn verified_figures(&self) -> String {
+        match &self.figures {
+            Some(figures) => format!("- These figures and ratios were computed from the statements and are correct, use them instead of working them out yourself:\n{}", figures),
+            None => String::new()
+        }
+    }
+
+    /// Computes the ratios of the statements, writing them to `ratios.json`.
+    /// Without any, a `ratios.json` from an earlier run is removed.
+    fn compute_ratios(&mut self, statement_file: &str, income: Option<&FinancialStatement>, balance_sheet: Option<&FinancialStatement>, cash_flow: Option<&FinancialStatement>) -> Result<(), GenericError> {
+        let dir = format!("{}/analysis", statement_file);
+        let output = format!("{}/ratios.json", dir);
+
+        let ratios = ratios::compute(income, balance_sheet, cash_flow);
+        if ratios.periods.is_empty() {
+            self.figures = None;
+            remove_outputs(&[&output])?;
+            return Ok(());
+        }
+
+        std::fs::create_dir_all(&dir)?;
+        serde_json::to_string_pretty(&ratios)?.write_to_file(&output)?;
+
+        let figures = ratios.to_prompt();
+        self.figures = if figures.is_empty() { None } else { Some(figures) };
+        Ok(())
+    }
+
+    /// Values the company with a DCF, writing the numbers to `valuation.json`
+    /// and, with the model's explanation of them, to `valuation.md`. The
+    /// explanation is only asked for again when the numbers change.
+    fn value(&mut self, statement_file: &str, manifest: &mut Manifest, income: Option<&FinancialStatement>, balance_sheet: Option<&FinancialStatement>, cash_flow: Option<&FinancialStatement>) -> Result<(), GenericError> {
+        let valuation = valuation::value(income, balance_sheet, cash_flow, &self.valuation)?;
+
+        std::fs::create_dir_all(format!("{}/analysis", statement_file))?;
+        let numbers = format!("{}/analysis/valuation.json", statement_file);
+        serde_json::to_string_pretty(&valuation)?.write_to_file(&numbers)?;
+
+        let tables = valuation.to_markdown();
+        let growth = if self.valuation.growth.is_some() {
+            "The forecast growth was set by hand"
+        } else {
+            "The forecast growth is the historical growth of the free cash flow (or else of revenue), kept between -5% and 15%"
+        };
+
+        self.stage(manifest, "valuation", &[Path::new(&numbers)], &format!("{}/analysis/valuation.md", statement_file), |fin, _| {
+            let prompt = format!(r#"
+            - The following is a discounted cash flow valuation of the stock ticker {}, computed from its cash flow statement and balance sheet.
+            - {}. The WACC and terminal growth are set by hand.
+            - Explain it for someone who is not financially savvy: what the free cash flow, growth, WACC and terminal growth assumptions mean and whether they look reasonable for this company, how the scenarios differ, and what the sensitivity table says about how much the value depends on them.
+            - Point out which assumptions matter most and what would have to be true for each scenario.
+            - It is imperative for you to respect and avoid tampering with financial figures. Do not recompute or change any of them.
+            - Please write in paragraphs and use spaces to make things easier to read.
+            {}
+            "#, fin.ticker, growth, tables);
+
+            let explanation = fin.ask(&prompt, false)?;
+            Ok(format!("# Valuation of {}\n\n{}\n\n{}", fin.ticker, explanation.trim(), tables))
+        })
     }
 
-    pub fn run(&mut self) -> Result<(), GenericError>{
+    /// Has the model pull the figures out of a pasted statement the table
+    /// parser can't read, in the form `import` writes.
+    fn extract_statement(&self, statement: &str, kind: StatementKind) -> Result<FinancialStatement, GenericError> {
+        let schema = json!({
+            "type": "object",
+            "required": ["scale", "periods", "line_items"],
+            "properties": {
+                "currency": { "type": ["string", "null"] },
+                "scale": { "type": "number" },
+                "periods": { "type": "array", "items": { "type": "string" } },
+                "line_items": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "required": ["name", "values"],
+                        "properties": {
+                            "name": { "type": "string" },
+                            "values": { "type": "array", "items": { "type": ["number", "null"] } }
+                        }
+                    }
+                }
+            }
+        });
+
+        let prompt = format!(r#"
+        - Extract every line item of the following {} for the stock ticker {}.
+        - List the periods in the order the statement shows them, e.g. FY2023 or Q3 2024.
+        - Give each line item one value per period, null where the statement has none.
+        - Copy the figures exactly, as plain numbers without thousands separators, negative numbers for figures in parentheses.
+        - Give as scale what the figures are multiplied by to get units: 1000000 for a statement in millions, 1000 for thousands, 1 when it doesn't say.
+        - The statement is as follows: {}
+        "#, kind.name(), self.ticker, statement);
+
+        let statement: FinancialStatement = self.llm.prompt_as(&prompt, Some(&schema), &self.model)?;
+        statement.check()?;
+        Ok(statement)
+    }
+
+    /// Extracts the figures of every pasted statement that isn't a table
+    /// `statements::parse` reads, for the ratios and the valuation. A
+    /// statement that doesn't need it, or whose extraction fails, is left
+    /// without an extracted file so an earlier one can't stand in for it.
+    fn extract_statements(&mut self, statement_file: &str, manifest: &mut Manifest) -> Result<(), GenericError> {
+        for kind in StatementKind::ALL {
+            let path = statements::statement_file(Path::new(statement_file), kind);
+            let output = extracted_file(statement_file, kind);
+            let stage = format!("{}_extraction", kind.file_stem());
+
+            let pasted = path.extension().is_some_and(|extension| extension == "txt");
+            let text = if pasted { std::fs::read_to_string(&path).unwrap_or_default() } else { String::new() };
+            if text.trim().is_empty() || statements::parse(&text).is_ok() {
+                remove_outputs(&[&output])?;
+                manifest.remove(&stage);
+                continue;
+            }
+
+            let result = self.stage(manifest, &stage, &[path.as_path()], &output, |fin, _| {
+                Ok(serde_json::to_string_pretty(&fin.extract_statement(&text, kind)?)?)
+            });
+            if let Err(e) = result {
+                eprintln!("WARNING: could not extract the figures of the {}: {}", kind.name(), e);
+                remove_outputs(&[&output])?;
+                manifest.remove(&stage);
+            }
+        }
+
+        manifest.save()
+    }
+
+    /// Analyses the ticker's statements and reports, picking up where an
+    /// earlier run stopped unless `fresh` is set.
+    pub fn run(&mut self, fresh: bool) -> Result<(), GenericError>{
         self.llm.system = Some(r#"
         Please keep the following in mind when I ask you questions from now on: 
         Act as a financial analyst named Mustafa. Mustafa has an MBA in Finance, and he is a CFA. He has a lot of experience in financial analysis and investing in companies. He is dedicated to helping companies and teams with their company analysis. Mustafa believes in 360 analysis with very detailed overviews of the company's leadership teams, qualitative research, quantitative research and financial research.
@@ -57,174 +210,260 @@ impl Finance{
 
         reports/ (Can contain the annual and quaterly reports)
 
+        Statements imported with `llm_search import` (CSV, JSON or
+        SEC XBRL filings) are used instead of the .txt files.
+
         And proceed forwards by pressing ENTER.
 
         "#);
 
-        io::stdout().flush().unwrap(); // Flush the stdout buffer to ensure the prompt is printed
-        io::stdin().read_line(&mut input)
-            .expect("Failed to read line");
-
-        let statement_file = "/Users/mmuhammad/Documents/financials/";
+        io::stdout().flush()?; // Flush the stdout buffer to ensure the prompt is printed
+        io::stdin().read_line(&mut input)?;
 
-        let statement_file = format!("{}{}",statement_file,self.ticker);
+        let statement_file = format!("{}/{}", self.data_root, self.ticker);
 
-        self.aggregate_data(&statement_file)?;
+        self.aggregate_data(&statement_file, fresh)?;
 
         Ok(())
     }
 
-    fn read_income_statements(&mut self, mut file: String) -> Result<String, GenericError>{
-
-        file.push_str("/income_statement.txt");
-
-        let file = std::fs::read_to_string(file)?;
+    fn read_income_statements(&mut self, path: &Path) -> Result<String, GenericError>{
+        let file = statement_text(path)?;
 
         let prompt = format!(r#"
         - I want you analyze the provided income statement in detail for the stock ticker {}
         - I want to break information down by both annual and quarter.
         - The income statement is as follows: {}
+        {}
         - Please write in paragraphs and use spaces to make things easier to read.
         - It is imperative for each heading to be on a new line.
         - Make a detailed report of your findings.
-        "#, self.ticker, file);
+        "#, self.ticker, file, self.verified_figures());
 
-        let output = self.llm.prompt(Some(prompt.trim().to_string()), Model::LLMA70b, false)?;
+        let output = self.ask(&prompt, false)?;
 
         Ok(output)
     }
     
-    fn read_cash_flow_statement(&self, mut file: String) -> Result<String, GenericError>{
-        file.push_str("/cash_flow_statement.txt");
-
-        let file = std::fs::read_to_string(file)?;
+    fn read_cash_flow_statement(&self, path: &Path) -> Result<String, GenericError>{
+        let file = statement_text(path)?;
 
         let prompt = format!(r#"
         - I want you analyze the provided cash flow statement in detail for the stock ticker {}
         - I want to break information down by both annual and quarter.
         - The cash flow statement is as follows: {}
+        {}
         - Please write in paragraphs and use spaces to make things easier to read.
         - It is imperative for each heading to be on a new line.
         - It is imperative for you to respect and avoid tampering with financial figures. It is imperitive to not interchange millions and billions, and substitute a comma with a period and so on.
         - Make a detailed report of your findings.
-        "#, self.ticker, file);
+        "#, self.ticker, file, self.verified_figures());
 
-        let output = self.llm.prompt(Some(prompt.trim().to_string()), Model::LLMA70b, false)?;
+        let output = self.ask(&prompt, false)?;
 
         Ok(output)
     }
 
-    fn read_balance_sheet(&self, mut file: String) -> Result<String, GenericError> {
-        file.push_str("/balance_sheet_statement.txt");
-
-        let file = std::fs::read_to_string(file)?;
+    fn read_balance_sheet(&self, path: &Path) -> Result<String, GenericError> {
+        let file = statement_text(path)?;
 
         let prompt = format!(r#"
         - I want you analyze the provided balance sheet statement in detail for the stock ticker {}
         - I want to break information down by both annual and quarter.
         - The balance sheet statement is as follows: {}
+        {}
         - Please write in paragraphs and use spaces to make things easier to read.
         - It is imperative for each heading to be on a new line.
         - Make a detailed report of your findings.
-        "#, self.ticker, file);
+        "#, self.ticker, file, self.verified_figures());
 
-        let output = self.llm.prompt(Some(prompt.trim().to_string()), Model::LLMA70b, false)?;
+        let output = self.ask(&prompt, false)?;
 
         Ok(output)
     }
 
-    fn read_report(&self, path: String) -> Result<String, GenericError>{
-        use poppler::Document;
-
-        let mut content = Vec::new();
+    /// Summarizes a report PDF in chunks of pages, then the chunk summaries
+    /// into sections and an overview, as Markdown citing pages throughout.
+    fn read_report(&self, path: String, name: &str, stage: &str, manifest: &mut Manifest) -> Result<String, GenericError>{
+        let pages = pdf_pages(Path::new(&path))?;
+
+        // Half the window for the pages leaves room for the prompt and reply
+        let window = self.model.context_window as usize;
+        let mut llm = self.llm.clone();
+        llm.max_tokens = llm.max_tokens.min(window as i32 / 4);
+        let finance = Finance { llm, ..self.clone() };
+
+        let ask = |prompt: &str| finance.ask(prompt, false);
+        let summarizer = Summarizer { budget: (window / 2).min(MAX_CHUNK_TOKENS), ask: &ask };
+
+        let done = manifest.chunks(stage).to_vec();
+        let summary = summarizer.summarize(name, &pages, &done, &mut |chunk| {
+            manifest.add_chunk(stage, chunk.clone());
+            if let Err(e) = manifest.save() {
+                eprintln!("WARNING: could not checkpoint {}: {}", name, e);
+            }
+        })?;
 
-        let mut summaries: Vec<String> = Vec::new();
+        Ok(summary.to_markdown(name))
+    }
 
-        let path : &Path = Path::new(&path);
+    /// Runs `run` and writes what it returns to `output`, unless the manifest
+    /// says an earlier run already did so from the same `inputs` with the
+    /// same model and `output` is still there.
+    fn stage(&mut self, manifest: &mut Manifest, name: &str, inputs: &[&Path], output: &str, run: impl FnOnce(&mut Self, &mut Manifest) -> Result<String, GenericError>) -> Result<(), GenericError>{
+        let stage = Stage::new(&self.model.id, inputs)?;
 
-        File::open(path)
-            .and_then(|mut file| file.read_to_end(&mut content))
-            .map_err(|e| {
-                eprintln!("ERROR: could not read file {}", e);
-                Box::new(e) as Box<dyn std::error::Error>
-            })?;
-    
-        let pdf = Document::from_data(&content, None).map_err(|e| {
-            eprintln!("ERROR: could not read file");
-            Box::new(e) as Box<dyn std::error::Error>
-        })?;
-    
-        let n = pdf.n_pages();
-        for i in 0..n {
-            let page = pdf.page(i).expect(&format!("{i} is within the bounds of the range of the page"));
-            if let Some(content) = page.text() {
-                let prompt = format!(r#"
-                - You are being given investment information page by page.
-                - I want you to scan through the information.
-                - I want you explain whats being said on the page and summarize it into something easily digestable for someone that is not financially literate or savvy.
-                - It is imperative for you to respect and avoid tampering with financial figures. It is imperitive to not interchange millions and billions, and substitute a comma with a period and so on.
-                - It is imperitative to follow this format:
-                **PAGE NUMBER: **
-                **REPORT: **
-                - current page number {} => {}
-                "#, i, content.to_string());
-                let model = Model::LLMA70b;
-                let output = self.llm.prompt(Some(prompt.trim().to_string()), model, true);
-                let output = match output{
-                    Ok(res) => res,
-                    Err(_) => {
-                        println!("ERROR: Rerun prompt .. {}", i);
-                        sleep(Duration::from_secs(120));
-                        let model = Model::LLMA70b;
-                        self.llm.prompt(Some(prompt.trim().to_string()), model, true).unwrap()
-                    }
-                };
-                summaries.push(output);
-                println!("");
-            }
-            sleep(Duration::from_secs(15));
-            let summary_path = format!("/Users/mmuhammad/Documents/financials/{}/analysis/summaries.txt", self.ticker);
-            let summaries_string = summaries.to_string()?;
-            summaries_string.write_to_file(&summary_path)?;
+        if manifest.is_done(name, &stage) && Path::new(output).exists() {
+            println!("  {} is up to date", name);
+            return Ok(());
         }
 
-        let summaries_string = summaries.to_string()?;
+        manifest.start(name, stage);
+        manifest.save()?;
+
+        run(self, manifest)?.write_to_file(output)?;
 
-        Ok(summaries_string)
+        manifest.complete(name);
+        manifest.save()?;
 
+        Ok(())
     }
 
-    fn aggregate_data(&mut self, statement_file : &str) -> Result<(), GenericError>{
+    fn aggregate_data(&mut self, statement_file : &str, fresh: bool) -> Result<(), GenericError>{
+        let manifest_path = Path::new(statement_file).join("analysis").join("manifest.json");
+        let mut manifest = if fresh { Manifest::fresh(manifest_path) } else { Manifest::load(manifest_path) };
+
+        // Imported statements take the place of pasted ones
+        let income = statements::statement_file(Path::new(statement_file), StatementKind::Income);
+        let cash_flow = statements::statement_file(Path::new(statement_file), StatementKind::CashFlow);
+        let balance_sheet = statements::statement_file(Path::new(statement_file), StatementKind::BalanceSheet);
+
+        println!("Extracting figures ..");
+        self.extract_statements(statement_file, &mut manifest)?;
+
+        let income_statement = read_statement(statement_file, StatementKind::Income);
+        let balance_sheet_statement = read_statement(statement_file, StatementKind::BalanceSheet);
+        let cash_flow_statement = read_statement(statement_file, StatementKind::CashFlow);
+
+        println!("Computing ratios ..");
+        self.compute_ratios(statement_file, income_statement.as_ref(), balance_sheet_statement.as_ref(), cash_flow_statement.as_ref())?;
+
+        // Every analysis quotes the ratios, which draw on all three statements
+        let ratios_file = Path::new(statement_file).join("analysis").join("ratios.json");
+        let ratios_input = ratios_file.exists().then_some(ratios_file.as_path());
 
         println!("Reading income statement ..");
-        let income_analysis = self.read_income_statements(statement_file.to_string())?;
-        income_analysis.write_to_file(&format!("{}/analysis/{}", statement_file, "income_analysis.txt"))?;
-        sleep(Duration::from_secs(30));
+        self.stage(&mut manifest, "income_analysis", &with_ratios(&income, ratios_input), &format!("{}/analysis/{}", statement_file, "income_analysis.txt"),
+            |fin, _| fin.read_income_statements(&income))?;
         println!("Reading cash flow statement ..");
-        let cash_flow_analysis = self.read_cash_flow_statement(statement_file.to_string())?;
-        cash_flow_analysis.write_to_file(&format!("{}/analysis/{}", statement_file, "cash_flow_analysis.txt"))?;
-        sleep(Duration::from_secs(30));
+        self.stage(&mut manifest, "cash_flow_analysis", &with_ratios(&cash_flow, ratios_input), &format!("{}/analysis/{}", statement_file, "cash_flow_analysis.txt"),
+            |fin, _| fin.read_cash_flow_statement(&cash_flow))?;
         println!("Reading balance sheet statement ..");
-        let balance_sheet_analyis = self.read_balance_sheet(statement_file.to_string())?;
-        balance_sheet_analyis.write_to_file(&format!("{}/analysis/{}", statement_file, "balance_sheet_analysis.txt"))?;
+        self.stage(&mut manifest, "balance_sheet_analysis", &with_ratios(&balance_sheet, ratios_input), &format!("{}/analysis/{}", statement_file, "balance_sheet_analysis.txt"),
+            |fin, _| fin.read_balance_sheet(&balance_sheet))?;
+        println!("Valuing ..");
+        if let Err(e) = self.value(statement_file, &mut manifest, income_statement.as_ref(), balance_sheet_statement.as_ref(), cash_flow_statement.as_ref()) {
+            eprintln!("WARNING: skipping the valuation: {}", e);
+            // An earlier run's valuation would pass for a current one
+            remove_outputs(&[&format!("{}/analysis/valuation.json", statement_file), &format!("{}/analysis/valuation.md", statement_file)])?;
+            manifest.remove("valuation");
+            manifest.save()?;
+        }
         println!("Reading Reports ..");
 
-        let reports= std::fs::read_dir(format!("/Users/mmuhammad/Documents/financials/{}/reports", self.ticker))?;
+        let reports= std::fs::read_dir(format!("{}/reports", statement_file))?;
 
         for report in reports{
             let report_name = format!("{}", report.unwrap().file_name().to_str().unwrap());
-            let report_path = format!("/Users/mmuhammad/Documents/financials/{}/reports/{}", self.ticker, report_name);
-            let output = match self.read_report(report_path){
-                Ok(output) => output,
-                Err(_) => {
-                    continue
-                }
-            };
-            output.write_to_file(&format!("{}/analysis/{}", statement_file, report_name))?;
+            let report_path = format!("{}/reports/{}", statement_file, report_name);
+            let stem = Path::new(&report_name).file_stem().and_then(|stem| stem.to_str()).unwrap_or(&report_name);
+            let output = format!("{}/analysis/{}_summary.md", statement_file, stem);
+            let stage = format!("report:{}", report_name);
+
+            let result = self.stage(&mut manifest, &stage, &[Path::new(&report_path)], &output,
+                |fin, manifest| fin.read_report(report_path.clone(), &report_name, &stage, manifest));
+            if let Err(e) = result {
+                eprintln!("WARNING: could not summarize {}: {}", report_name, e);
+            }
         }
 
         Ok(())
 
     }
 
-}
\ No newline at end of file
+}
+
+/// Deletes `paths` that exist.
+fn remove_outputs(paths: &[&str]) -> Result<(), GenericError> {
+    for path in paths {
+        match std::fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(format!("could not remove {}: {}", path, e).into()),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn with_ratios<'a>(statement: &'a Path, ratios: Option<&'a Path>) -> Vec<&'a Path> {
+    std::iter::once(statement).chain(ratios).collect()
+}
+
+/// Where the figures the model extracted from a pasted `kind` statement go.
+fn extracted_file(statement_file: &str, kind: StatementKind) -> String {
+    format!("{}/analysis/{}_extracted.json", statement_file, kind.file_stem())
+}
+
+/// The imported, pasted or extracted `kind` statement of a ticker, if there
+/// is one and its figures can be read.
+fn read_statement(statement_file: &str, kind: StatementKind) -> Option<FinancialStatement> {
+    let extracted = extracted_file(statement_file, kind);
+    let path = if Path::new(&extracted).exists() {
+        extracted.into()
+    } else {
+        statements::statement_file(Path::new(statement_file), kind)
+    };
+    if std::fs::metadata(&path).is_ok_and(|metadata| metadata.len() == 0) {
+        return None;
+    }
+    FinancialStatement::read(&path)
+        .map_err(|e| eprintln!("WARNING: could not read the figures in {}: {}", path.display(), e))
+        .ok()
+}
+
+/// A statement for a prompt: imported ones as a table, pasted ones as they
+/// are.
+fn statement_text(path: &Path) -> Result<String, GenericError> {
+    if path.extension().is_some_and(|extension| extension == "json") {
+        Ok(FinancialStatement::read(path)?.to_text())
+    } else {
+        Ok(std::fs::read_to_string(path)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extracted_figures_stand_in_for_a_prose_statement() {
+        let dir = std::env::temp_dir().join(format!("llm_search_finance_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("analysis")).unwrap();
+        let ticker = dir.to_str().unwrap();
+        std::fs::write(dir.join("income_statement.txt"), "Revenue was 383 billion dollars in fiscal 2023.").unwrap();
+
+        assert!(read_statement(ticker, StatementKind::Income).is_none());
+
+        let extracted = FinancialStatement {
+            currency: Some("USD".to_string()),
+            scale: 1e9,
+            periods: vec!["FY2023".to_string()],
+            line_items: vec![statements::LineItem { name: "Revenue".to_string(), values: vec![Some(383.0)] }]
+        };
+        std::fs::write(extracted_file(ticker, StatementKind::Income), serde_json::to_string(&extracted).unwrap()).unwrap();
+
+        let statement = read_statement(ticker, StatementKind::Income).unwrap();
+        assert_eq!(statement.value(ratios::REVENUE, 0), Some(383e9));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
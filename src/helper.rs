@@ -1,27 +1,14 @@
 use std::fs::File;
-use std::io::Write;
+use std::io::{Read, Write};
+use std::path::Path;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use crate::chat::ContentPart;
 use crate::GenericError;
 
 pub trait ToDocument {
     fn write_to_file(&self, file_name: &str) -> Result<(), GenericError>;
 }
 
-pub trait ToString{
-    fn to_string(&self) -> Result<String, GenericError>;
-}
-
-impl ToString for Vec<String>{
-    fn to_string(&self) -> Result<String, GenericError> {
-        let mut summaries = String::new();
-
-        for summary in self{
-            summaries.push_str(&summary);
-        }
-
-        Ok(summaries)
-    }
-}
-
 impl ToDocument for Vec<String> {
     fn write_to_file(&self, file_name: &str) -> Result<(), GenericError> {
         let mut file = File::create(file_name)?;
@@ -46,6 +33,99 @@ impl ToDocument for String{
     }
 }
 
+/// Text of every page of a PDF that has any, with its zero based page number.
+pub fn pdf_pages(path: &Path) -> Result<Vec<(i32, String)>, GenericError> {
+    use poppler::Document;
+
+    let content = std::fs::read(path)
+        .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+
+    let pdf = Document::from_data(&content, None)
+        .map_err(|e| format!("could not open {} as a PDF: {}", path.display(), e))?;
+
+    let mut pages = Vec::new();
+
+    for i in 0..pdf.n_pages() {
+        if let Some(text) = pdf.page(i).and_then(|page| page.text()) {
+            pages.push((i, text.to_string()));
+        }
+    }
+
+    Ok(pages)
+}
+
+/// Reads a file to attach to a prompt. PDFs are converted to text, anything
+/// else has to be UTF-8.
+pub fn read_attachment(path: &Path) -> Result<String, GenericError> {
+    let is_pdf = path.extension().map(|ext| ext.eq_ignore_ascii_case("pdf")).unwrap_or(false);
+
+    if is_pdf {
+        let pages: Vec<String> = pdf_pages(path)?.into_iter()
+            .map(|(i, text)| format!("[page {}]\n{}", i + 1, text))
+            .collect();
+        return Ok(pages.join("\n\n"));
+    }
+
+    let bytes = std::fs::read(path)
+        .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+
+    String::from_utf8(bytes)
+        .map_err(|_| format!("{} is not a text file or PDF", path.display()).into())
+}
+
+/// The image at `path` as a content part, or `None` if it isn't a PNG, JPEG,
+/// GIF or WebP file.
+pub fn read_image(path: &Path) -> Result<Option<ContentPart>, GenericError> {
+    let extension = path.extension().map(|ext| ext.to_string_lossy().to_lowercase()).unwrap_or_default();
+
+    let media_type = match extension.as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        _ => return Ok(None)
+    };
+
+    let bytes = std::fs::read(path)
+        .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+
+    Ok(Some(ContentPart::Image { media_type: media_type.to_string(), data: BASE64_STANDARD.encode(bytes) }))
+}
+
+/// Whatever was piped or redirected into the process. `None` when stdin is
+/// anything else, such as a terminal or the open but silent stdin of cron,
+/// CI or `ssh host cmd`, which would never reach the end.
+pub fn read_piped_stdin() -> Result<Option<String>, GenericError> {
+    let mut stdin = std::io::stdin();
+
+    if !stdin_is_piped(&stdin) {
+        return Ok(None);
+    }
+
+    let mut input = String::new();
+    stdin.read_to_string(&mut input)?;
+
+    Ok(Some(input).filter(|input| !input.trim().is_empty()))
+}
+
+#[cfg(unix)]
+fn stdin_is_piped(stdin: &std::io::Stdin) -> bool {
+    use std::os::fd::AsFd;
+    use std::os::unix::fs::FileTypeExt;
+
+    stdin.as_fd().try_clone_to_owned()
+        .map(File::from)
+        .and_then(|file| file.metadata())
+        .is_ok_and(|metadata| metadata.file_type().is_fifo() || metadata.file_type().is_file())
+}
+
+#[cfg(not(unix))]
+fn stdin_is_piped(stdin: &std::io::Stdin) -> bool {
+    use std::io::IsTerminal;
+
+    !stdin.is_terminal()
+}
+
 pub fn serve_files(file_dir: &str) {
     use std::process::Command;
 
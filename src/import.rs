@@ -0,0 +1,461 @@
+use std::collections::HashMap;
+use std::path::Path;
+use chrono::NaiveDate;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+
+use crate::statements::{self, FinancialStatement, LineItem, StatementKind};
+use crate::GenericError;
+
+/// Line items taken from XBRL facts: the label they get, the US GAAP
+/// concepts tried in order, and whether the figure is an outflow reported as
+/// a positive payment.
+type Concepts = &'static [(&'static str, &'static [&'static str], bool)];
+
+const INCOME_CONCEPTS: Concepts = &[
+    ("Total Revenue", &["Revenues", "RevenueFromContractWithCustomerExcludingAssessedTax", "SalesRevenueNet", "RevenueFromContractWithCustomerIncludingAssessedTax"], false),
+    ("Cost of Revenue", &["CostOfRevenue", "CostOfGoodsAndServicesSold", "CostOfGoodsSold"], false),
+    ("Gross Profit", &["GrossProfit"], false),
+    ("Research and Development", &["ResearchAndDevelopmentExpense"], false),
+    ("Selling, General and Administrative", &["SellingGeneralAndAdministrativeExpense"], false),
+    ("Operating Expenses", &["OperatingExpenses"], false),
+    ("Operating Income", &["OperatingIncomeLoss"], false),
+    ("Interest Expense", &["InterestExpense", "InterestExpenseNonoperating"], false),
+    ("Pretax Income", &["IncomeLossFromContinuingOperationsBeforeIncomeTaxesExtraordinaryItemsNoncontrollingInterest", "IncomeLossFromContinuingOperationsBeforeIncomeTaxesMinorityInterestAndIncomeLossFromEquityMethodInvestments"], false),
+    ("Tax Provision", &["IncomeTaxExpenseBenefit"], false),
+    ("Net Income", &["NetIncomeLoss", "ProfitLoss"], false),
+    ("Diluted Average Shares", &["WeightedAverageNumberOfDilutedSharesOutstanding"], false)
+];
+
+const BALANCE_SHEET_CONCEPTS: Concepts = &[
+    ("Cash and Cash Equivalents", &["CashAndCashEquivalentsAtCarryingValue"], false),
+    ("Inventory", &["InventoryNet"], false),
+    ("Total Current Assets", &["AssetsCurrent"], false),
+    ("Total Assets", &["Assets"], false),
+    ("Current Debt", &["DebtCurrent", "LongTermDebtCurrent"], false),
+    ("Total Current Liabilities", &["LiabilitiesCurrent"], false),
+    ("Long Term Debt", &["LongTermDebtNoncurrent"], false),
+    ("Total Liabilities", &["Liabilities"], false),
+    ("Stockholders Equity", &["StockholdersEquity", "StockholdersEquityIncludingPortionAttributableToNoncontrollingInterest"], false)
+];
+
+const CASH_FLOW_CONCEPTS: Concepts = &[
+    ("Depreciation and Amortization", &["DepreciationDepletionAndAmortization", "DepreciationAndAmortization", "DepreciationAmortizationAndAccretionNet"], false),
+    ("Stock Based Compensation", &["ShareBasedCompensation"], false),
+    ("Operating Cash Flow", &["NetCashProvidedByUsedInOperatingActivities"], false),
+    ("Capital Expenditure", &["PaymentsToAcquirePropertyPlantAndEquipment", "PaymentsToAcquireProductiveAssets"], true),
+    ("Investing Cash Flow", &["NetCashProvidedByUsedInInvestingActivities"], false),
+    ("Repurchase of Capital Stock", &["PaymentsForRepurchaseOfCommonStock"], true),
+    ("Cash Dividends Paid", &["PaymentsOfDividends", "PaymentsOfDividendsCommonStock"], true),
+    ("Financing Cash Flow", &["NetCashProvidedByUsedInFinancingActivities"], false)
+];
+
+/// Reads the statements in `path`, going by its extension: `.csv` and
+/// `.json` hold one statement, `kind` or guessed from the file name, while
+/// XBRL (`.xml`, `.xbrl`) and inline XBRL (`.htm`, `.html`, `.xhtml`) filings
+/// hold all three.
+pub fn import(path: &Path, kind: Option<StatementKind>) -> Result<Vec<(StatementKind, FinancialStatement)>, GenericError> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+    let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or("").to_lowercase();
+
+    let single = |statement: Result<FinancialStatement, String>| -> Result<Vec<(StatementKind, FinancialStatement)>, GenericError> {
+        let file_name = path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+        let kind = kind.or_else(|| StatementKind::guess(&file_name))
+            .ok_or_else(|| format!("can't tell which statement {} is, pass --statement", file_name))?;
+        Ok(vec![(kind, statement.map_err(|e| format!("{}: {}", path.display(), e))?)])
+    };
+
+    match extension.as_str() {
+        "csv" => single(from_csv(&text)),
+        "json" => single(statements::from_json(&text)),
+        "xml" | "xbrl" | "htm" | "html" | "xhtml" => {
+            let statements = from_xbrl(&text).map_err(|e| format!("{}: {}", path.display(), e))?;
+            Ok(statements.into_iter().filter(|(found, _)| kind.is_none_or(|kind| kind == *found)).collect())
+        }
+        _ => Err(format!("don't know how to import {}, expected a .csv, .json, .xml or .htm file", path.display()).into())
+    }
+}
+
+/// A statement exported as CSV: a header row of periods, then one row per
+/// line item, as Yahoo Finance and spreadsheets export them.
+pub fn from_csv(text: &str) -> Result<FinancialStatement, String> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(text.as_bytes());
+
+    let mut rows = Vec::new();
+    for record in reader.records() {
+        let record = record.map_err(|e| e.to_string())?;
+        let cells: Vec<String> = record.iter().map(|cell| cell.trim().to_string()).collect();
+        // Trailing empty cells would count as missing figures
+        let end = cells.iter().rposition(|cell| !cell.is_empty()).map_or(0, |last| last + 1);
+        rows.push(cells[..end].to_vec());
+    }
+
+    statements::from_rows(rows)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Period {
+    Instant(NaiveDate),
+    Duration(NaiveDate, NaiveDate)
+}
+
+#[derive(Default)]
+struct Context {
+    id: String,
+    start: Option<NaiveDate>,
+    end: Option<NaiveDate>,
+    instant: Option<NaiveDate>,
+    /// Facts about a segment or scenario rather than the whole company.
+    dimensional: bool
+}
+
+struct Fact {
+    concept: String,
+    context: String,
+    unit: String,
+    scale: i32,
+    negative: bool,
+    format: String,
+    text: String,
+    depth: usize
+}
+
+/// The income statement, balance sheet and cash flow statement of an SEC
+/// filing, from its XBRL instance or its inline XBRL document. Only facts
+/// about the whole company are used, and for the income and cash flow
+/// statements the period closest to a year ending on each date. Figures are
+/// in units.
+pub fn from_xbrl(text: &str) -> Result<Vec<(StatementKind, FinancialStatement)>, String> {
+    let mut reader = Reader::from_str(text);
+
+    let mut contexts: HashMap<String, Period> = HashMap::new();
+    let mut currencies: HashMap<String, String> = HashMap::new();
+    let mut done: Vec<Fact> = Vec::new();
+
+    let mut context: Option<Context> = None;
+    let mut unit: Option<(String, Vec<String>)> = None;
+    let mut field = String::new();
+    let mut fact: Option<Fact> = None;
+
+    loop {
+        let event = reader.read_event().map_err(|e| format!("not valid XML at byte {}: {}", reader.buffer_position(), e))?;
+        match event {
+            Event::Start(element) => {
+                let name = local_name(&element);
+                if let Some(fact) = fact.as_mut() {
+                    fact.depth += 1;
+                    continue;
+                }
+
+                match name.as_str() {
+                    "context" => context = Some(Context { id: attribute(&element, "id").unwrap_or_default(), ..Context::default() }),
+                    "segment" | "scenario" => if let Some(context) = context.as_mut() { context.dimensional = true },
+                    "unit" => unit = Some((attribute(&element, "id").unwrap_or_default(), Vec::new())),
+                    _ => {}
+                }
+                field = name;
+
+                if let (Some(context), Some(unit)) = (attribute(&element, "contextRef"), attribute(&element, "unitRef")) {
+                    let concept = attribute(&element, "name").unwrap_or_else(|| qualified_name(&element));
+                    fact = Some(Fact {
+                        concept: concept.rsplit(':').next().unwrap_or(&concept).to_string(),
+                        context,
+                        unit,
+                        scale: attribute(&element, "scale").and_then(|scale| scale.parse().ok()).unwrap_or(0),
+                        negative: attribute(&element, "sign").is_some_and(|sign| sign == "-"),
+                        format: attribute(&element, "format").unwrap_or_default(),
+                        text: String::new(),
+                        depth: 1
+                    });
+                }
+            }
+            Event::Empty(element) => {
+                if let ("segment" | "scenario", Some(context)) = (local_name(&element).as_str(), context.as_mut()) {
+                    context.dimensional = true;
+                }
+            }
+            Event::Text(content) => {
+                // Leave entities XML doesn't know, like &nbsp;, as they are
+                let content = content.unescape().map(|text| text.to_string())
+                    .unwrap_or_else(|_| String::from_utf8_lossy(&content).to_string());
+                if let Some(fact) = fact.as_mut() {
+                    fact.text.push_str(&content);
+                    continue;
+                }
+
+                let date = || NaiveDate::parse_from_str(content.trim(), "%Y-%m-%d").ok();
+                match (field.as_str(), context.as_mut(), unit.as_mut()) {
+                    ("startDate", Some(context), _) => context.start = date(),
+                    ("endDate", Some(context), _) => context.end = date(),
+                    ("instant", Some(context), _) => context.instant = date(),
+                    ("measure", _, Some((_, measures))) => measures.push(content.trim().to_string()),
+                    _ => {}
+                }
+            }
+            Event::End(element) => {
+                field.clear();
+                if let Some(current) = fact.as_mut() {
+                    current.depth -= 1;
+                    if current.depth > 0 {
+                        continue;
+                    }
+                    done.extend(fact.take());
+                    continue;
+                }
+
+                match name(element.local_name().as_ref()).as_str() {
+                    "context" => {
+                        let Some(done) = context.take() else { continue };
+                        let period = match (done.instant, done.start, done.end) {
+                            (Some(instant), _, _) => Some(Period::Instant(instant)),
+                            (None, Some(start), Some(end)) => Some(Period::Duration(start, end)),
+                            _ => None
+                        };
+                        if let (Some(period), false) = (period, done.dimensional) {
+                            contexts.insert(done.id, period);
+                        }
+                    }
+                    "unit" => {
+                        if let Some((id, measures)) = unit.take() {
+                            if let [measure] = measures.as_slice() {
+                                if let Some(currency) = measure.strip_prefix("iso4217:") {
+                                    currencies.insert(id, currency.to_string());
+                                }
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    if done.is_empty() {
+        return Err("no XBRL facts found".to_string());
+    }
+
+    // Inline XBRL may define its contexts after the facts using them, so
+    // facts are only matched to periods now. A fact can appear more than
+    // once, the first one counts.
+    let mut facts: HashMap<(String, Period), f64> = HashMap::new();
+    let mut currency = None;
+    for fact in done {
+        let (Some(period), Some(value)) = (contexts.get(&fact.context), fact_value(&fact)) else { continue };
+        if currency.is_none() {
+            currency = currencies.get(&fact.unit).cloned();
+        }
+        facts.entry((fact.concept, *period)).or_insert(value);
+    }
+
+    let statements: Vec<(StatementKind, FinancialStatement)> = [
+        (StatementKind::Income, INCOME_CONCEPTS),
+        (StatementKind::BalanceSheet, BALANCE_SHEET_CONCEPTS),
+        (StatementKind::CashFlow, CASH_FLOW_CONCEPTS)
+    ].into_iter()
+        .filter_map(|(kind, concepts)| {
+            let statement = build(kind, concepts, &facts, currency.clone())?;
+            Some((kind, statement))
+        })
+        .collect();
+
+    if statements.is_empty() {
+        return Err("none of the facts belong to the income statement, balance sheet or cash flow statement".to_string());
+    }
+
+    Ok(statements)
+}
+
+/// One statement from `facts`, newest period first.
+fn build(kind: StatementKind, concepts: Concepts, facts: &HashMap<(String, Period), f64>, currency: Option<String>) -> Option<FinancialStatement> {
+    let known = |concept: &str| concepts.iter().any(|(_, names, _)| names.contains(&concept));
+    let instant = kind == StatementKind::BalanceSheet;
+
+    let mut periods: Vec<Period> = facts.keys()
+        .filter(|(concept, period)| known(concept) && matches!(period, Period::Instant(_)) == instant)
+        .map(|(_, period)| *period)
+        .collect();
+    periods.sort_by_key(|period| std::cmp::Reverse(end(period)));
+    periods.dedup();
+
+    // Of the durations ending on the same day (a quarter and the year to
+    // date, say), keep the one closest to a year
+    let mut chosen: Vec<Period> = Vec::new();
+    for period in periods {
+        match chosen.last_mut() {
+            Some(last) if end(last) == end(&period) => {
+                if distance_from_a_year(&period) < distance_from_a_year(last) {
+                    *last = period;
+                }
+            }
+            _ => chosen.push(period)
+        }
+    }
+
+    let line_items: Vec<LineItem> = concepts.iter()
+        .map(|(label, names, outflow)| {
+            let values = chosen.iter()
+                .map(|period| {
+                    names.iter()
+                        .find_map(|name| facts.get(&(name.to_string(), *period)))
+                        .map(|value| if *outflow { -value.abs() } else { *value })
+                })
+                .collect();
+            LineItem { name: label.to_string(), values }
+        })
+        .filter(|item| item.values.iter().any(Option::is_some))
+        .collect();
+
+    if line_items.is_empty() {
+        return None;
+    }
+
+    Some(FinancialStatement {
+        currency,
+        scale: 1.0,
+        periods: chosen.iter().map(|period| end(period).format("%Y-%m-%d").to_string()).collect(),
+        line_items
+    })
+}
+
+fn end(period: &Period) -> NaiveDate {
+    match period {
+        Period::Instant(date) | Period::Duration(_, date) => *date
+    }
+}
+
+fn distance_from_a_year(period: &Period) -> i64 {
+    match period {
+        Period::Duration(start, end) => ((*end - *start).num_days() - 365).abs(),
+        Period::Instant(_) => 0
+    }
+}
+
+/// The figure a fact states, in units. Inline XBRL shows it formatted, with
+/// a scale and sign given separately.
+fn fact_value(fact: &Fact) -> Option<f64> {
+    let text = fact.text.trim();
+    let format = fact.format.to_lowercase();
+
+    let value = if format.contains("zero") || matches!(text, "-" | "—" | "–") {
+        0.0
+    } else if format.contains("comma-decimal") || format.contains("numcommadecimal") {
+        text.replace(['.', ' ', '\u{a0}'], "").replace(',', ".").parse().ok()?
+    } else {
+        text.replace([',', ' ', '\u{a0}'], "").parse().ok()?
+    };
+
+    let value = value * 10f64.powi(fact.scale);
+    Some(if fact.negative { -value } else { value })
+}
+
+fn attribute(element: &BytesStart, name: &str) -> Option<String> {
+    element.attributes()
+        .flatten()
+        .find(|attribute| attribute.key.local_name().as_ref() == name.as_bytes())
+        .and_then(|attribute| attribute.unescape_value().ok())
+        .map(|value| value.to_string())
+}
+
+fn local_name(element: &BytesStart) -> String {
+    name(element.local_name().as_ref())
+}
+
+fn qualified_name(element: &BytesStart) -> String {
+    name(element.name().as_ref())
+}
+
+fn name(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn imports_a_csv_export() {
+        let csv = "\
+name,ttm,12/31/2023,12/31/2022,
+TotalRevenue,\"1,000\",900,800,
+\"Cost Of Revenue, Total\",(600),-540,,
+";
+        let statement = from_csv(csv).unwrap();
+
+        assert_eq!(statement.periods, ["ttm", "12/31/2023", "12/31/2022"]);
+        assert_eq!(statement.line_items[0].values, [Some(1000.0), Some(900.0), Some(800.0)]);
+        assert_eq!(statement.line_items[1].name, "Cost Of Revenue, Total");
+        assert_eq!(statement.line_items[1].values, [Some(-600.0), Some(-540.0), None]);
+    }
+
+    #[test]
+    fn imports_an_xbrl_instance() {
+        let xbrl = r#"<?xml version="1.0" encoding="utf-8"?>
+<xbrli:xbrl xmlns:xbrli="http://www.xbrl.org/2003/instance" xmlns:us-gaap="http://fasb.org/us-gaap/2023" xmlns:xbrldi="http://xbrl.org/2006/xbrldi">
+  <xbrli:context id="FY2023"><xbrli:entity><xbrli:identifier scheme="http://www.sec.gov/CIK">0000320193</xbrli:identifier></xbrli:entity>
+    <xbrli:period><xbrli:startDate>2022-09-25</xbrli:startDate><xbrli:endDate>2023-09-30</xbrli:endDate></xbrli:period></xbrli:context>
+  <xbrli:context id="Q4"><xbrli:entity><xbrli:identifier scheme="http://www.sec.gov/CIK">0000320193</xbrli:identifier></xbrli:entity>
+    <xbrli:period><xbrli:startDate>2023-07-02</xbrli:startDate><xbrli:endDate>2023-09-30</xbrli:endDate></xbrli:period></xbrli:context>
+  <xbrli:context id="FY2022"><xbrli:entity><xbrli:identifier scheme="http://www.sec.gov/CIK">0000320193</xbrli:identifier></xbrli:entity>
+    <xbrli:period><xbrli:startDate>2021-09-26</xbrli:startDate><xbrli:endDate>2022-09-24</xbrli:endDate></xbrli:period></xbrli:context>
+  <xbrli:context id="FY2023_iPhone"><xbrli:entity><xbrli:identifier scheme="http://www.sec.gov/CIK">0000320193</xbrli:identifier>
+    <xbrli:segment><xbrldi:explicitMember dimension="srt:ProductOrServiceAxis">aapl:IPhoneMember</xbrldi:explicitMember></xbrli:segment></xbrli:entity>
+    <xbrli:period><xbrli:startDate>2022-09-25</xbrli:startDate><xbrli:endDate>2023-09-30</xbrli:endDate></xbrli:period></xbrli:context>
+  <xbrli:context id="I2023"><xbrli:entity><xbrli:identifier scheme="http://www.sec.gov/CIK">0000320193</xbrli:identifier></xbrli:entity>
+    <xbrli:period><xbrli:instant>2023-09-30</xbrli:instant></xbrli:period></xbrli:context>
+  <xbrli:unit id="usd"><xbrli:measure>iso4217:USD</xbrli:measure></xbrli:unit>
+  <us-gaap:RevenueFromContractWithCustomerExcludingAssessedTax contextRef="FY2023_iPhone" unitRef="usd" decimals="-6">200583000000</us-gaap:RevenueFromContractWithCustomerExcludingAssessedTax>
+  <us-gaap:RevenueFromContractWithCustomerExcludingAssessedTax contextRef="FY2023" unitRef="usd" decimals="-6">383285000000</us-gaap:RevenueFromContractWithCustomerExcludingAssessedTax>
+  <us-gaap:RevenueFromContractWithCustomerExcludingAssessedTax contextRef="Q4" unitRef="usd" decimals="-6">89498000000</us-gaap:RevenueFromContractWithCustomerExcludingAssessedTax>
+  <us-gaap:RevenueFromContractWithCustomerExcludingAssessedTax contextRef="FY2022" unitRef="usd" decimals="-6">394328000000</us-gaap:RevenueFromContractWithCustomerExcludingAssessedTax>
+  <us-gaap:NetIncomeLoss contextRef="FY2023" unitRef="usd" decimals="-6">96995000000</us-gaap:NetIncomeLoss>
+  <us-gaap:PaymentsToAcquirePropertyPlantAndEquipment contextRef="FY2023" unitRef="usd" decimals="-6">10959000000</us-gaap:PaymentsToAcquirePropertyPlantAndEquipment>
+  <us-gaap:Assets contextRef="I2023" unitRef="usd" decimals="-6">352583000000</us-gaap:Assets>
+  <us-gaap:RevenueRecognitionPolicyTextBlock contextRef="FY2023">Revenue is recognized when ...</us-gaap:RevenueRecognitionPolicyTextBlock>
+</xbrli:xbrl>"#;
+
+        let statements = from_xbrl(xbrl).unwrap();
+        let get = |kind| &statements.iter().find(|(found, _)| *found == kind).unwrap().1;
+
+        let income = get(StatementKind::Income);
+        assert_eq!(income.currency.as_deref(), Some("USD"));
+        assert_eq!(income.periods, ["2023-09-30", "2022-09-24"]);
+        assert_eq!(income.value(&["Total Revenue"], 0), Some(383285000000.0));
+        assert_eq!(income.value(&["Total Revenue"], 1), Some(394328000000.0));
+        assert_eq!(income.value(&["Net Income"], 1), None);
+
+        assert_eq!(get(StatementKind::CashFlow).value(&["Capital Expenditure"], 0), Some(-10959000000.0));
+        assert_eq!(get(StatementKind::BalanceSheet).periods, ["2023-09-30"]);
+    }
+
+    #[test]
+    fn imports_inline_xbrl() {
+        let html = r#"<html xmlns="http://www.w3.org/1999/xhtml" xmlns:ix="http://www.xbrl.org/2013/inlineXBRL" xmlns:xbrli="http://www.xbrl.org/2003/instance">
+<body>
+  <table><tr>
+    <td>Net sales</td><td>$&#160;<ix:nonFraction name="us-gaap:Revenues" contextRef="c-1" unitRef="usd" scale="6" decimals="-6" format="ixt:num-dot-decimal">383,285</ix:nonFraction></td>
+    <td>Net loss</td><td>(<ix:nonFraction name="us-gaap:NetIncomeLoss" contextRef="c-1" unitRef="usd" scale="6" decimals="-6" sign="-" format="ixt:num-dot-decimal"><span>1,200</span></ix:nonFraction>)</td>
+    <td>Dividends</td><td><ix:nonFraction name="us-gaap:PaymentsOfDividends" contextRef="c-1" unitRef="usd" scale="6" format="ixt:fixed-zero">—</ix:nonFraction></td>
+  </tr></table>
+  <div style="display:none"><ix:header><ix:resources>
+    <xbrli:context id="c-1"><xbrli:entity><xbrli:identifier scheme="http://www.sec.gov/CIK">0000320193</xbrli:identifier></xbrli:entity>
+      <xbrli:period><xbrli:startDate>2022-09-25</xbrli:startDate><xbrli:endDate>2023-09-30</xbrli:endDate></xbrli:period></xbrli:context>
+    <xbrli:unit id="usd"><xbrli:measure>iso4217:USD</xbrli:measure></xbrli:unit>
+  </ix:resources></ix:header></div>
+</body>
+</html>"#;
+
+        let statements = from_xbrl(html).unwrap();
+
+        let (kind, income) = &statements[0];
+        assert_eq!(*kind, StatementKind::Income);
+        assert_eq!(income.value(&["Total Revenue"], 0), Some(383285e6));
+        assert_eq!(income.value(&["Net Income"], 0), Some(-1200e6));
+        assert_eq!(statements[1].1.value(&["Cash Dividends Paid"], 0), Some(0.0));
+    }
+}
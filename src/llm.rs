@@ -1,245 +1,752 @@
 use std::io::{self, Write};
-use reqwest;
-use std::{collections::HashMap, env};
-use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
 use std::thread::sleep;
-use std::time::Duration;
-
-use crate::GenericError;
+use std::time::{Duration, Instant};
+use reqwest::blocking::{RequestBuilder, Response};
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::cache::{self, ResponseCache};
+use crate::backend::{ChatBackend, ChatRequest, Completion, ResponseFormat, Sampling, Usage};
+use crate::chat::{ChatMessage, ContentPart, Conversation, Role};
+use crate::error::LlmError;
+use crate::models::ModelInfo;
+use crate::session::{Session, Turn};
+use crate::tokens::{self, ContextUsage};
+use crate::tools::Tools;
+use crate::usage::UsageMeter;
+
+/// Text wrapped around single queries, set under `[decoration]` in the
+/// config. Nothing is added unless configured.
+#[derive(Deserialize, Default, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Decoration {
+    /// Put in front of the query.
+    pub prefix: Option<String>,
+    /// Put after the query, e.g. "Keep the answer short."
+    pub suffix: Option<String>,
+    /// Start of the reply for the model to carry on from, on backends that
+    /// support it. It is part of the returned reply.
+    pub prefill: Option<String>
+}
 
 #[derive(Clone)]
 pub struct LLM {
     pub system: Option<String>,
     pub prompt: Option<String>,
-    pub model: Option<Model>
+    pub max_tokens: i32,
+    pub sampling: Sampling,
+    pub decoration: Decoration,
+    pub meter: Arc<UsageMeter>,
+    /// Replies to reuse instead of sending the same request again.
+    pub cache: Option<ResponseCache>,
+    pub backend: Arc<dyn ChatBackend>
 }
 
-#[derive(Serialize, Debug)]
-struct Payload{
-    messages: Vec<HashMap<String, String>>,
-    model: String,
-    max_tokens: i32
-}
+impl LLM {
+    pub fn new(backend: Arc<dyn ChatBackend>) -> LLM {
+        Self{
+            system: None,
+            prompt: None,
+            max_tokens: 8192,
+            sampling: Sampling::default(),
+            decoration: Decoration::default(),
+            meter: Arc::new(UsageMeter::default()),
+            cache: None,
+            backend
+        }
+    }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Choices {
-    choices: Vec<Choice>,
-}
+    /// Sends `input` as the next turn of `session`, streaming the reply to
+    /// stdout, and records the turn once the reply is complete.
+    pub fn context_prompt(&self, input: &str, look_back: Option<usize>, summarize: bool, model: &ModelInfo, session: &mut Session) -> Result<ContextUsage, LlmError>{
+        let (mut prepared_prompt, mut usage) = self.prepare_prompt(session, input, look_back, model);
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Choice {
-    index: i32,
-    message: Message,
-    logprobs: Option<serde_json::Value>, // Assuming logprobs can be null or some JSON structure
-    finish_reason: String,
-}
+        if summarize && usage.dropped > 0 {
+            self.summarize_turns(session, usage.dropped, model)?;
+            (prepared_prompt, usage) = self.prepare_prompt(session, input, look_back, model);
+        }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Message {
-    role: String,
-    content: String,
-}
+        let message = self.check(self.send(&prepared_prompt, model, Some(&mut print_delta))?)?;
 
-#[derive(Debug, Clone)]
-pub enum Model{
-    LLMA8b,
-    LLMA70b,
-    MISTRAL,
-    GEMMA7b,
-    GEMMA9b
-}
+        println!();
 
-impl Into<String> for &Model {
-    fn into(self) -> String {
-        match *self {
-            Model::LLMA8b => String::from("llama3-8b-8192"),
-            Model::LLMA70b => String::from("llama3-70b-8192"),
-            Model::MISTRAL => String::from("mixtral-8x7b-32768"),
-            Model::GEMMA7b => String::from("gemma-7b-it"),
-            Model::GEMMA9b => String::from("gemma-9b-it"),
-        }
+        session.turns.push(Turn { user: input.to_string(), assistant: message });
+
+        Ok(usage)
     }
-}
 
-impl LLM {
-    pub fn new() -> LLM {
-        Self{
-            system: None,
-            prompt: None,
-            model: None
+    /// Context window use of the session as it stands, without a new turn.
+    pub fn context_usage(&self, session: &Session, look_back: Option<usize>, model: &ModelInfo) -> ContextUsage{
+        self.prepare_prompt(session, "", look_back, model).1
+    }
+
+    /// Builds the request for the next turn, keeping as many of the most recent
+    /// turns as fit in the model's context window once room for the reply is
+    /// set aside. Older turns are left out (or were folded into the session
+    /// summary by `summarize_turns`).
+    fn prepare_prompt(&self, session: &Session, input: &str, look_back: Option<usize>, model: &ModelInfo) -> (ChatRequest, ContextUsage){
+        let mut conversation = Conversation::default();
+
+        let window = model.context_window as usize;
+        let max_tokens = self.max_tokens(model) as usize;
+        // Models like llama3-70b-8192 allow as much output as they have context,
+        // so never reserve more than half the window for the reply.
+        let budget = window - max_tokens.min(window / 2);
+
+        if let Some(system) = &self.system {
+            conversation.system(system);
+        }
+
+        if let Some(summary) = &session.summary {
+            conversation.system(&format!("Summary of the earlier conversation:\n{}", summary));
+        }
+
+        let texts: Vec<String> = conversation.messages().iter().map(|m| m.text_content()).collect();
+        let mut used = tokens::count_messages(texts.iter().map(|text| text.as_str()).chain([input]));
+
+        let turns = &session.turns[session.summarized..];
+        let mut kept = 0;
+
+        for turn in turns.iter().rev().take(look_back.unwrap_or(turns.len())) {
+            let cost = tokens::count_message(&turn.user) + tokens::count_message(&turn.assistant);
+            if used + cost > budget {
+                break;
+            }
+            used += cost;
+            kept += 1;
         }
+
+        if used > budget {
+            eprintln!("WARNING: the prompt alone takes {} of the {} tokens available", used, budget);
+        }
+
+        for turn in &turns[turns.len() - kept..]{
+            conversation.user(&turn.user).assistant(&turn.assistant);
+        }
+
+        conversation.user(input);
+
+        let request = self.request(&conversation, model, max_tokens.min(window.saturating_sub(used).max(1)) as i32);
+
+        (request, ContextUsage { used, window, dropped: turns.len() - kept })
+
     }
 
-    pub fn context_prompt(&self, look_back: usize, model:Model) -> Result<(), GenericError>{
-        let mut look_back_window : Vec<String> = Vec::new();
-        let mut look_back_input_window : Vec<String> = Vec::new();
-        let mut look_back_assistant_window : Vec<String> = Vec::new();
+    /// Folds the `count` oldest unsummarized turns into the session summary.
+    fn summarize_turns(&self, session: &mut Session, count: usize, model: &ModelInfo) -> Result<(), LlmError>{
+        let start = session.summarized;
+        let mut transcript = String::new();
 
-        let mut message = String::from("");
-        loop{
-            let mut input = String::new();
+        if let Some(summary) = &session.summary {
+            transcript.push_str(&format!("Summary so far:\n{}\n\n", summary));
+        }
 
-            print!("Please enter some input: ");
-            io::stdout().flush().unwrap(); // Flush the stdout buffer to ensure the prompt is printed
-            io::stdin().read_line(&mut input)
-                .expect("Failed to read line");
-            
-            look_back_assistant_window.push(message.to_string());
-            look_back_input_window.push(input.to_string());
+        for turn in &session.turns[start..start + count] {
+            transcript.push_str(&format!("User: {}\nAssistant: {}\n\n", turn.user, turn.assistant));
+        }
+
+        let prompt = format!(r#"
+        - Summarize the following conversation so it can stand in for it later on.
+        - Keep every fact, figure, decision and open question, drop the small talk.
+        - Write it as a few short paragraphs.
+        {}
+        "#, transcript);
 
-            let prepared_prompt = self.prepare_prompt(&look_back_input_window, &look_back_assistant_window, &model);
+        let mut conversation = Conversation::default();
+        conversation.user(prompt.trim());
 
-            let groq_api_key = env::var_os("GROQ_API_KEY")
-                .expect("Key Not Found")
-                .into_string()
-                .unwrap();
+        let request = self.request(&conversation, model, self.max_tokens(model).min(1024));
 
-            let url = "https://api.groq.com/openai/v1/chat/completions";
+        session.summary = Some(self.check(self.send(&request, model, None)?)?);
+        session.summarized += count;
 
-            let request = reqwest::blocking::Client::new();
+        Ok(())
+    }
+
+    pub fn prompt(&self, query : Option<String>, model: &ModelInfo, output: bool) -> Result<String, LlmError>{
+        self.prompt_parts(text_parts(query), model, output)
+    }
 
-            let response = request.post(url)
-            .header("Authorization", format!("Bearer {}", groq_api_key))
-            .header("CONTENT_TYPE", "application/json")
-            .json(&prepared_prompt)
-            .send()?;
+    /// Like `prompt`, for a query made of several parts such as text and
+    /// images.
+    pub fn prompt_parts(&self, parts: Vec<ContentPart>, model: &ModelInfo, output: bool) -> Result<String, LlmError>{
+        if output {
+            let message = self.prompt_stream(parts, model, &mut print_delta)?;
+            println!();
+            return Ok(message);
+        }
 
-            let response : Choices = response.json()?;
+        self.check(self.query(parts, model, None)?)
+    }
 
-            message = response.choices.get(0).expect("").message.content.to_string();
+    /// Streams the reply to a single query, calling `on_delta` with each piece
+    /// of text as it arrives. Returns the full reply once the stream ends.
+    pub fn prompt_stream(&self, parts: Vec<ContentPart>, model: &ModelInfo, on_delta: &mut dyn FnMut(&str)) -> Result<String, LlmError>{
+        self.check(self.query(parts, model, Some(on_delta))?)
+    }
 
-            let delay = Duration::from_millis(15); // Adjust the delay as needed
+    /// Sends a single query and hands back the whole completion, including
+    /// token usage, leaving it to the caller to deal with truncation.
+    pub fn complete(&self, query : Option<String>, model: &ModelInfo) -> Result<Completion, LlmError>{
+        self.query(text_parts(query), model, None)
+    }
 
-            for char in message.chars() {
-                print!("\x1b[38;2;255;100;0m{}\x1b[0m", char); // Orange color
-                io::stdout().flush().unwrap(); // Flush stdout to ensure the character is printed immediately
-                sleep(delay);
+    /// Sends a single query, streaming when `on_delta` is given. The prefill,
+    /// if any, goes out first and is put in front of the reply.
+    fn query(&self, parts: Vec<ContentPart>, model: &ModelInfo, on_delta: Option<&mut dyn FnMut(&str)>) -> Result<Completion, LlmError>{
+        let request = self.prepare_query(parts, model);
+        let prefill = self.prefill();
+
+        let mut completion = match on_delta {
+            Some(on_delta) => {
+                if let Some(prefill) = prefill {
+                    on_delta(prefill);
+                }
+                self.send(&request, model, Some(on_delta))?
             }
+            None => self.send(&request, model, None)?
+        };
+
+        if let Some(prefill) = prefill {
+            completion.content.insert_str(0, prefill);
+        }
+
+        Ok(completion)
+    }
+
+    /// Asks for a JSON answer to `query`, see `prompt_as`.
+    pub fn prompt_json(&self, query: &str, schema: Option<&Value>, model: &ModelInfo) -> Result<Value, LlmError>{
+        self.prompt_as(query, schema, model)
+    }
+
+    /// Asks for a JSON answer to `query` and deserializes it into `T`. The
+    /// reply is checked against `schema` if given, and a reply that isn't
+    /// valid JSON, doesn't match the schema or doesn't fit `T` is sent back
+    /// with what was wrong so the model can correct it.
+    pub fn prompt_as<T: DeserializeOwned>(&self, query: &str, schema: Option<&Value>, model: &ModelInfo) -> Result<T, LlmError>{
+        let validator = match schema {
+            Some(schema) => Some(jsonschema::validator_for(schema)
+                .map_err(|e| LlmError::InvalidOutput(format!("invalid JSON Schema: {}", e)))?),
+            None => None
+        };
 
-            println!(); // Move to the next line after the animation is done
+        let mut instructions = String::from("Answer with a single JSON document and nothing else, no explanation and no code fences.");
+        if let Some(schema) = schema {
+            instructions.push_str(&format!("\nThe JSON must match this JSON Schema:\n{}", schema));
+        }
+
+        let mut conversation = Conversation::default();
+        if let Some(system) = &self.system {
+            conversation.system(system);
+        }
+        conversation.user(&format!("{}\n\n{}", query.trim(), instructions));
+
+        let mut errors = Vec::new();
+
+        for _ in 0..JSON_ATTEMPTS {
+            let mut request = self.request(&conversation, model, self.max_tokens(model));
+            request.response_format = Some(schema.map(|schema| ResponseFormat::Schema(schema.clone())).unwrap_or(ResponseFormat::Json));
+
+            let reply = self.check(self.send(&request, model, None)?)?;
+
+            errors = match parse_json(&reply) {
+                Ok(value) => {
+                    let invalid: Vec<String> = validator.iter()
+                        .flat_map(|validator| validator.iter_errors(&value))
+                        .map(|e| format!("at '{}': {}", e.instance_path, e))
+                        .collect();
+
+                    if invalid.is_empty() {
+                        match serde_json::from_value(value) {
+                            Ok(parsed) => return Ok(parsed),
+                            Err(e) => vec![e.to_string()]
+                        }
+                    } else {
+                        invalid
+                    }
+                }
+                Err(e) => vec![format!("not valid JSON: {}", e)]
+            };
+
+            conversation
+                .assistant(&reply)
+                .user(&format!("That reply is not usable:\n- {}\n{}", errors.join("\n- "), instructions));
+        }
+
+        Err(LlmError::InvalidOutput(errors.join("; ")))
+    }
+
+    /// Answers `query` letting the model call `tools` along the way. Each
+    /// round the requested calls are run and their results sent back, until
+    /// the model answers without calling anything or `max_iterations` rounds
+    /// have passed.
+    pub fn prompt_with_tools(&self, query: &str, tools: &Tools, model: &ModelInfo, max_iterations: usize) -> Result<String, LlmError>{
+        let mut conversation = Conversation::default();
+        if let Some(system) = &self.system {
+            conversation.system(system);
+        }
+        conversation.user(query.trim());
 
-            let count = &look_back_window.len();
+        for _ in 0..max_iterations {
+            let mut request = self.request(&conversation, model, self.max_tokens(model));
+            request.tools = tools.specs();
 
-            if count > &look_back{
-                look_back_window.push(message.to_string());
-                look_back_window.remove(0);
+            let completion = self.send(&request, model, None)?;
+
+            if completion.tool_calls.is_empty() {
+                return self.check(completion);
             }
-            else{
-                look_back_window.push(message.to_string());
+
+            let mut reply = ChatMessage::text(Role::Assistant, &completion.content);
+            reply.tool_calls = completion.tool_calls.clone();
+            conversation.push(reply);
+
+            for call in completion.tool_calls {
+                eprintln!("\x1b[2m[{}({})]\x1b[0m", call.name, call.arguments);
+                conversation.push(ChatMessage::tool_result(&call.id, &tools.call(&call)));
             }
+        }
+
+        Err(LlmError::ToolLoop(max_iterations))
+    }
+
+    /// Sends `request`, streaming when `on_delta` is given, and records what
+    /// it used. Providers that don't report usage get it counted locally. A
+    /// cached reply is handed back as is, without a call or a usage record.
+    fn send(&self, request: &ChatRequest, model: &ModelInfo, on_delta: Option<&mut dyn FnMut(&str)>) -> Result<Completion, LlmError>{
+        let key = self.cache.as_ref().and_then(|_| cache::key(self.backend.name(), self.backend.endpoint(), request));
+
+        if let (Some(cache), Some(key)) = (&self.cache, &key) {
+            if let Some(completion) = cache.get(key) {
+                eprintln!("\x1b[2m[cached reply]\x1b[0m");
+                if let Some(on_delta) = on_delta {
+                    on_delta(&completion.content);
+                }
+                return Ok(completion);
+            }
+        }
+
+        let completion = match on_delta {
+            Some(on_delta) => self.backend.stream(request, on_delta)?,
+            None => self.backend.complete(request)?
+        };
 
+        // Only keep replies worth getting again
+        let complete = !completion.truncated() && (!completion.content.trim().is_empty() || !completion.tool_calls.is_empty());
+        if let (Some(cache), Some(key), true) = (&self.cache, &key, complete) {
+            cache.put(key, &completion);
         }
 
+        match completion.usage {
+            Some(usage) => self.meter.record(model, usage, false),
+            None => {
+                let texts: Vec<String> = request.messages.iter().map(|m| m.text_content()).collect();
+                let usage = Usage {
+                    prompt_tokens: tokens::count_messages(texts.iter().map(|text| text.as_str())) as u32,
+                    completion_tokens: tokens::count(&completion.content) as u32
+                };
+                self.meter.record(model, usage, true);
+            }
+        }
+
+        Ok(completion)
     }
 
-    fn prepare_prompt(&self, look_back_input_window: &Vec<String>, look_back_assistant_window: &Vec<String>, model: &Model) -> Payload{
-        let mut prompt_assistant_vec : Vec<HashMap<String, String>> = Vec::new();
-        let mut prompt_input_vec : Vec<HashMap<String, String>> = Vec::new();
+    /// Turns a completion that was cut off or came back empty into an error.
+    fn check(&self, completion: Completion) -> Result<String, LlmError> {
+        let provider = self.backend.name().to_string();
 
-        let mut result_vec : Vec<HashMap<String, String>> = Vec::new();
+        if completion.truncated() {
+            Err(LlmError::Truncated { provider, partial: completion.content })
+        } else if completion.content.trim().is_empty() {
+            Err(LlmError::EmptyResponse { provider })
+        } else {
+            Ok(completion.content)
+        }
+    }
 
-        for prev_response in look_back_assistant_window{
-            let mut assistant_hashmap : HashMap<String, String>= HashMap::new();
-            assistant_hashmap.insert("role".to_string(), "assistant".to_string());
-            assistant_hashmap.insert("content".to_string(), prev_response.to_string());
+    fn prepare_query(&self, parts: Vec<ContentPart>, model: &ModelInfo) -> ChatRequest{
+        let mut conversation = Conversation::default();
 
-            prompt_assistant_vec.push(assistant_hashmap);
+        if let Some(system) = &self.system {
+            conversation.system(system);
         }
 
-        for prev_response in look_back_input_window{
-            let mut user_map: HashMap<String, String> = HashMap::new();
+        conversation
+            .user(self.decoration.prefix.as_deref().unwrap_or_default())
+            .push(ChatMessage::new(Role::User, parts))
+            .user(self.decoration.suffix.as_deref().unwrap_or_default())
+            .assistant(self.prefill().unwrap_or_default());
+
+        self.request(&conversation, model, self.max_tokens(model))
+    }
+
+    fn request(&self, conversation: &Conversation, model: &ModelInfo, max_tokens: i32) -> ChatRequest {
+        let mut request = conversation.request(&model.id, max_tokens);
+        request.sampling = self.sampling.clone();
+        request
+    }
+
+    /// The configured prefill, if the backend lets a reply start from one.
+    /// Trailing whitespace is dropped since Anthropic refuses it.
+    fn prefill(&self) -> Option<&str> {
+        self.decoration.prefill.as_deref()
+            .map(str::trim_end)
+            .filter(|prefill| !prefill.is_empty() && self.backend.supports_prefill())
+    }
+
+    fn max_tokens(&self, model: &ModelInfo) -> i32 {
+        self.max_tokens.min(model.max_output_tokens as i32)
+    }
+}
+
+// How often `prompt_as` asks before giving up on a reply it can't use.
+const JSON_ATTEMPTS: usize = 3;
 
-            user_map.insert("role".to_string(), "user".to_string());
-            user_map.insert("content".to_string(), prev_response.to_string());
+/// Parses a JSON reply, looking past code fences or a sentence of preamble
+/// that models add even when told not to.
+fn parse_json(reply: &str) -> Result<Value, serde_json::Error> {
+    let reply = reply.trim();
 
-            prompt_input_vec.push(user_map);
+    serde_json::from_str(reply).or_else(|e| {
+        let start = reply.find(['{', '[']);
+        let end = reply.rfind(['}', ']']);
+        match (start, end) {
+            (Some(start), Some(end)) if start < end => serde_json::from_str(&reply[start..=end]),
+            _ => Err(e)
         }
+    })
+}
 
+fn text_parts(query: Option<String>) -> Vec<ContentPart> {
+    vec![ContentPart::Text(query.unwrap_or_default())]
+}
 
-        let mut system_map: HashMap<String, String> = HashMap::new();
+fn print_delta(delta: &str) {
+    print!("\x1b[38;2;255;100;0m{}\x1b[0m", delta); // Orange color
+    io::stdout().flush().unwrap(); // Flush stdout to ensure the text is printed immediately
+}
+
+/// How the backends deal with rate limits and flaky connections. Requests
+/// that fail with 429, 5xx, a timeout or a dropped connection are retried
+/// with exponential backoff and jitter, waiting at least as long as the
+/// provider asks for via `retry-after` or the `x-ratelimit-reset-*` headers.
+/// Anything else (bad key, bad request, unknown model) fails straight away.
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    // Set when the provider reports an exhausted quota on a successful
+    // response, so the next request waits for the window to reset instead
+    // of burning a retry on a guaranteed 429.
+    not_before: Mutex<Option<Instant>>
+}
 
-        if self.system.is_some(){
-            system_map.insert("role".to_string(), "system".to_string());
-            let system_resp = self.system.clone().unwrap().to_string();
-            system_map.insert("content".to_string(), system_resp);
-            result_vec.push(system_map);
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 6,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(120),
+            not_before: Mutex::new(None)
         }
+    }
+}
 
-        for i in 0 .. prompt_assistant_vec.len(){
-            result_vec.push(prompt_assistant_vec.get(i).unwrap().clone());
-            result_vec.push(prompt_input_vec.get(i).unwrap().clone());
+impl RetryPolicy {
+    pub fn send(&self, request: impl Fn() -> RequestBuilder) -> Result<Response, LlmError> {
+        let mut attempt = 0;
+
+        loop {
+            self.wait_for_quota();
+
+            let wait = match request().send() {
+                Ok(response) if response.status().is_success() => {
+                    self.track_quota(response.headers());
+                    return Ok(response);
+                }
+                Ok(response) => {
+                    let status = response.status();
+                    if !is_retryable_status(status) || attempt >= self.max_retries {
+                        let body = response.text().unwrap_or_default();
+                        return Err(LlmError::http(status.as_u16(), body));
+                    }
+                    let backoff = self.backoff(attempt);
+                    let wait = rate_limit_delay(response.headers())
+                        .map(|requested| requested.max(backoff))
+                        .unwrap_or(backoff);
+                    eprintln!("WARNING: provider returned {}, retrying in {:.1}s", status, wait.as_secs_f32());
+                    wait
+                }
+                Err(e) => {
+                    if !is_transient(&e) || attempt >= self.max_retries {
+                        return Err(LlmError::Network(e));
+                    }
+                    let wait = self.backoff(attempt);
+                    eprintln!("WARNING: {}, retrying in {:.1}s", e, wait.as_secs_f32());
+                    wait
+                }
+            };
+
+            sleep(wait);
+            attempt += 1;
         }
+    }
 
-        let model_str: String = (model).into();
+    fn backoff(&self, attempt: u32) -> Duration {
+        let exponential = self.base_delay.saturating_mul(2u32.saturating_pow(attempt)).min(self.max_delay);
+        // Equal jitter: half the delay is fixed, the other half is random
+        exponential / 2 + exponential.mul_f64(jitter() / 2.0)
+    }
 
-        Payload{
-            model: model_str,
-            messages: result_vec,
-            max_tokens: 8192
+    fn wait_for_quota(&self) {
+        let not_before = self.not_before.lock().unwrap().take();
+        if let Some(not_before) = not_before {
+            let now = Instant::now();
+            if not_before > now {
+                sleep(not_before - now);
+            }
         }
+    }
+
+    fn track_quota(&self, headers: &HeaderMap) {
+        let exhausted = |kind: &str| header_str(headers, &format!("x-ratelimit-remaining-{}", kind))
+            .map(|remaining| remaining.trim() == "0")
+            .unwrap_or(false);
+
+        let reset = ["requests", "tokens"].iter()
+            .filter(|kind| exhausted(kind))
+            .filter_map(|kind| header_str(headers, &format!("x-ratelimit-reset-{}", kind)))
+            .filter_map(parse_reset)
+            .max();
 
+        if let Some(reset) = reset {
+            *self.not_before.lock().unwrap() = Some(Instant::now() + reset.min(self.max_delay));
+        }
     }
+}
 
-    pub fn prompt(&self, query : Option<String>, model: Model, output: bool) -> Result<String, GenericError>{
-        let groq_api_key = env::var_os("GROQ_API_KEY")
-            .expect("Key Not Found")
-            .into_string()
-            .unwrap();
+fn is_retryable_status(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS
+        || status == StatusCode::REQUEST_TIMEOUT
+        || status.is_server_error()
+}
 
-        let url = "https://api.groq.com/openai/v1/chat/completions";
+/// Timeouts, refused connections and connections dropped halfway are worth
+/// another try. A bad URL or a body that can't be sent fails the same way
+/// every time.
+fn is_transient(error: &reqwest::Error) -> bool {
+    if error.is_timeout() || error.is_connect() {
+        return true;
+    }
 
-        let request = reqwest::blocking::Client::new();
+    let mut source = std::error::Error::source(error);
+    while let Some(e) = source {
+        if let Some(io) = e.downcast_ref::<io::Error>() {
+            return matches!(io.kind(),
+                io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted | io::ErrorKind::BrokenPipe
+                | io::ErrorKind::UnexpectedEof | io::ErrorKind::TimedOut | io::ErrorKind::Interrupted);
+        }
+        source = e.source();
+    }
 
-        let mut user_map: HashMap<String, String> = HashMap::new();
+    false
+}
 
-        user_map.insert("role".to_string(), "user".to_string());
-        user_map.insert("content".to_string(), query.unwrap());
-        
-        
-        let mut vec : Vec<HashMap<String, String>>= Vec::new();
+fn header_str<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+/// The delay a rate limited response asks for. `retry-after` wins, otherwise
+/// the longest of Groq's request and token window resets.
+fn rate_limit_delay(headers: &HeaderMap) -> Option<Duration> {
+    if let Some(seconds) = header_str(headers, "retry-after").and_then(|v| v.trim().parse::<f64>().ok()) {
+        return Some(Duration::from_secs_f64(seconds.max(0.0)));
+    }
 
-        let mut system_map: HashMap<String, String> = HashMap::new();
-        system_map.insert("role".to_string(), "system".to_string());
-        let system = self.system.clone().unwrap().to_string();
-        system_map.insert("content".to_string(), system);
-        vec.push(system_map);
-        
-        vec.push(user_map);
+    ["x-ratelimit-reset-requests", "x-ratelimit-reset-tokens"].iter()
+        .filter_map(|name| header_str(headers, name))
+        .filter_map(parse_reset)
+        .max()
+}
 
-        let mut assistant_map: HashMap<String, String> = HashMap::new();
-        assistant_map.insert("role".to_string(), "assistant".to_string());
-        assistant_map.insert("content".to_string(), "Try to answer as concise as possible, I do not want to read large responses".to_string());
-        vec.push(assistant_map);
-        
-        let model_str: String = (&model).into();
+/// Parses Groq style reset durations such as `7.66s`, `120ms` or `2m59.56s`.
+fn parse_reset(value: &str) -> Option<Duration> {
+    let mut total = 0.0;
+    let mut number = String::new();
+    let mut chars = value.trim().chars().peekable();
 
-        let body = Payload{
-            model: model_str,
-            messages: vec,
-            max_tokens: 8192
+    while let Some(c) = chars.next() {
+        if c.is_ascii_digit() || c == '.' {
+            number.push(c);
+            continue;
+        }
+
+        let amount: f64 = number.parse().ok()?;
+        number.clear();
+
+        total += match c {
+            'h' => amount * 3600.0,
+            'm' if chars.peek() == Some(&'s') => {
+                chars.next();
+                amount / 1000.0
+            }
+            'm' => amount * 60.0,
+            's' => amount,
+            _ => return None
         };
+    }
 
-        let response = request.post(url)
-        .header("Authorization", format!("Bearer {}", groq_api_key))
-        .header("CONTENT_TYPE", "application/json")
-        .json(&body)
-        .send()?;
+    if !number.is_empty() {
+        // A bare number is seconds
+        total += number.parse::<f64>().ok()?;
+    }
 
-        let response : Choices = response.json()?;
+    Some(Duration::from_secs_f64(total))
+}
 
-        let message = response.choices.get(0).expect("").message.content.to_string();
+fn jitter() -> f64 {
+    // RandomState is seeded randomly per instance, which is all the
+    // randomness a backoff needs.
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(0);
+    (hasher.finish() % 10_000) as f64 / 10_000.0
+}
 
-        let delay = Duration::from_millis(5); // Adjust the delay as needed
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{self, Provider};
+    use crate::config::ProviderConfig;
 
-        if output == true{
-            for char in message.chars() {
-                print!("\x1b[38;2;255;100;0m{}\x1b[0m", char); // Orange color
-                io::stdout().flush().unwrap(); // Flush stdout to ensure the character is printed immediately
-                sleep(delay);
-            }
-            println!();
+    fn llm() -> LLM {
+        let mut llm = LLM::new(backend::from_provider(Provider::Ollama, &ProviderConfig::default()));
+        llm.system = Some("Be brief.".to_string());
+        llm
+    }
+
+    fn model(context_window: u32) -> ModelInfo {
+        ModelInfo {
+            id: "test-model".to_string(),
+            provider: Provider::Ollama,
+            context_window,
+            max_output_tokens: 100,
+            aliases: Vec::new(),
+            input_price: None,
+            output_price: None
         }
+    }
+
+    fn session(turns: &[(&str, &str)]) -> Session {
+        let mut session = Session::new("test-model", Some("Be brief.".to_string()));
+        session.turns = turns.iter()
+            .map(|(user, assistant)| Turn { user: user.to_string(), assistant: assistant.to_string() })
+            .collect();
+        session
+    }
+
+    fn roles(request: &ChatRequest) -> Vec<(Role, String)> {
+        request.messages.iter().map(|m| (m.role, m.text_content())).collect()
+    }
+
+    #[test]
+    fn single_query_ends_with_the_user() {
+        let request = llm().prepare_query(text_parts(Some("q".to_string())), &model(8192));
+
+        assert_eq!(roles(&request), vec![
+            (Role::System, "Be brief.".to_string()),
+            (Role::User, "q".to_string())
+        ]);
+    }
+
+    #[test]
+    fn decoration_wraps_the_query() {
+        let decoration = Decoration {
+            prefix: Some("Context first.".to_string()),
+            suffix: Some("Keep it short.".to_string()),
+            prefill: Some("Answer: ".to_string())
+        };
+
+        let mut ollama = llm();
+        ollama.decoration = decoration.clone();
+        let request = ollama.prepare_query(text_parts(Some("q".to_string())), &model(8192));
+
+        assert_eq!(roles(&request)[1..], [(Role::User, "Context first.\n\nq\n\nKeep it short.".to_string())]);
 
-        Ok(message)
+        let mut anthropic = LLM::new(backend::from_provider(Provider::Anthropic, &ProviderConfig::default()));
+        anthropic.decoration = decoration;
+        let request = anthropic.prepare_query(text_parts(Some("q".to_string())), &model(8192));
+
+        assert_eq!(roles(&request), vec![
+            (Role::User, "Context first.\n\nq\n\nKeep it short.".to_string()),
+            (Role::Assistant, "Answer:".to_string())
+        ]);
+    }
+
+    #[test]
+    fn multi_turn_session_alternates() {
+        let session = session(&[("q1", "a1"), ("q2", "a2")]);
+        let (request, usage) = llm().prepare_prompt(&session, "q3", None, &model(8192));
+
+        assert_eq!(roles(&request), vec![
+            (Role::System, "Be brief.".to_string()),
+            (Role::User, "q1".to_string()),
+            (Role::Assistant, "a1".to_string()),
+            (Role::User, "q2".to_string()),
+            (Role::Assistant, "a2".to_string()),
+            (Role::User, "q3".to_string())
+        ]);
+        assert_eq!(usage.dropped, 0);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn first_turn_has_no_assistant_message() {
+        let (request, _) = llm().prepare_prompt(&session(&[]), "q1", None, &model(8192));
+
+        assert_eq!(roles(&request), vec![
+            (Role::System, "Be brief.".to_string()),
+            (Role::User, "q1".to_string())
+        ]);
+    }
+
+    #[test]
+    fn summary_and_look_back_keep_the_order() {
+        let mut session = session(&[("q1", "a1"), ("q2", "a2"), ("q3", "a3")]);
+        session.summary = Some("q1 was answered".to_string());
+        session.summarized = 1;
+
+        let (request, usage) = llm().prepare_prompt(&session, "q4", Some(1), &model(8192));
+
+        assert_eq!(roles(&request), vec![
+            (Role::System, "Be brief.".to_string()),
+            (Role::System, "Summary of the earlier conversation:\nq1 was answered".to_string()),
+            (Role::User, "q3".to_string()),
+            (Role::Assistant, "a3".to_string()),
+            (Role::User, "q4".to_string())
+        ]);
+        assert_eq!(usage.dropped, 1);
+    }
+
+    #[test]
+    fn only_transient_send_errors_are_retried() {
+        let client = reqwest::blocking::Client::new();
+
+        let bad_url = client.get("http://exa mple.com/").send().unwrap_err();
+        assert!(!is_transient(&bad_url));
+
+        // Nothing listens on port 1
+        let refused = client.get("http://127.0.0.1:1/").send().unwrap_err();
+        assert!(is_transient(&refused));
+
+        let policy = RetryPolicy { base_delay: Duration::from_secs(60), ..RetryPolicy::default() };
+        let started = Instant::now();
+        assert!(policy.send(|| client.get("http://exa mple.com/")).is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -1,15 +1,58 @@
 mod llm;
+mod backend;
+mod chat;
+mod batch;
+mod cache;
+mod config;
+mod error;
+mod models;
+mod repl;
+mod session;
+mod summary;
+mod tokens;
+mod tools;
+mod usage;
+mod sse;
 mod finance;
 mod helper;
+mod import;
+mod manifest;
+mod ratios;
+mod statements;
+mod valuation;
 
+use backend::Sampling;
+use batch::Batch;
 use clap::{Parser, Subcommand};
+use config::Config;
+use error::LlmError;
 use finance::Finance;
-use helper::serve_files;
+use chat::ContentPart;
+use helper::{read_attachment, read_image, read_piped_stdin, serve_files};
+use models::{ModelInfo, ModelRegistry};
+use repl::Repl;
+use session::{Session, SessionStore};
+use statements::StatementKind;
+use std::sync::Arc;
+use usage::{GroupBy, UsageMeter};
 
 type GenericError = Box<dyn std::error::Error>;
 
+const EXIT_CODES: &str = "\
+Exit codes:
+  1  anything but a failed model call (config, I/O, statements, valuation)
+  3  missing API key
+  4  provider returned an HTTP error
+  5  network or connection failure
+  6  empty response
+  7  response cut off at max_tokens
+  8  response could not be decoded
+  9  unknown model name
+ 10  JSON response did not match its schema
+ 11  model kept calling tools without answering";
+
 #[derive(Parser)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, after_help = EXIT_CODES)]
 struct Cli {
     /// Optional name to operate on
     name: Option<String>,
@@ -18,6 +61,14 @@ struct Cli {
      #[arg(short, long, action = clap::ArgAction::Count)]
      debug: u8,
 
+    /// Config file to use instead of ~/.config/llm_search/config.toml
+    #[arg(long, global = true)]
+    config: Option<std::path::PathBuf>,
+
+    /// Always ask the model, neither reading nor writing the response cache
+    #[arg(long, global = true)]
+    no_cache: bool,
+
     #[command(subcommand)]
     command: Option<Commands>,
 }
@@ -27,20 +78,54 @@ struct Cli {
 enum Commands {
     #[clap(name = "query", about = "Send a single LLM query.")]
     Query {
-        #[clap(long, help = "Singular prompt with no context")]
-        prompt: String,
+        #[clap(long, help = "Singular prompt with no context, piped stdin is appended to it")]
+        prompt: Option<String>,
+        #[clap(long, help = "File to attach to the prompt (text, source code, PDF or image), can be repeated")]
+        file: Vec<std::path::PathBuf>,
         #[clap(long, help = "Optional model to use for the context")]
-        model: Option<String>
+        model: Option<String>,
+        #[clap(long, help = "Ask for the answer as JSON")]
+        json: bool,
+        #[clap(long, help = "JSON Schema file the answer has to match, implies --json")]
+        schema: Option<std::path::PathBuf>,
+        #[clap(long, conflicts_with_all = ["json", "schema"], help = "Let the model list and read the filings under the data root")]
+        tools: bool,
+        #[clap(long, default_value_t = 8, help = "Rounds of tool calls allowed before giving up")]
+        max_tool_rounds: usize,
+        #[command(flatten)]
+        sampling: SamplingArgs
+    },
+
+    #[clap(name = "batch", about = "Run every prompt in a JSONL file and write the answers as JSONL.")]
+    Batch {
+        #[clap(long, help = "JSONL file with one {\"prompt\", \"id\", \"system\", \"model\"} object per line")]
+        input: std::path::PathBuf,
+        #[clap(long, help = "JSONL file results are appended to, rows already answered there are skipped")]
+        output: std::path::PathBuf,
+        #[clap(long, default_value_t = 4, help = "How many requests to run at the same time")]
+        concurrency: usize,
+        #[clap(long, help = "Model for rows that don't name one")]
+        model: Option<String>,
+        #[clap(long, help = "System prompt for rows that don't set one")]
+        system: Option<String>
     },
 
     #[clap(name = "context", about = "Open a context to query the LLM with history kept intact.")]
     Context {
-        #[clap(long, help = "Decide the lookback window that gets used to preserve context")]
+        #[clap(long, help = "Send at most this many previous turns, on top of the model's context window limit")]
         look_back: Option<i32>,
+        #[clap(long, help = "Summarize turns that no longer fit in the context window instead of dropping them")]
+        summarize: bool,
         #[clap(long, help = "Optional model to use for the context")]
         model: Option<String>,
         #[clap(long, help = "Optional system prompt to decide how the LLM should respond")]
-        system: Option<String>
+        system: Option<String>,
+        #[clap(long, help = "Continue a saved conversation by its id")]
+        resume: Option<String>,
+        #[clap(long, help = "List saved conversations and exit")]
+        list: bool,
+        #[command(flatten)]
+        sampling: SamplingArgs
     },
 
     #[clap(name = "finance", about = "Perform a valuation for the stock in qs.")]
@@ -50,101 +135,292 @@ enum Commands {
         #[clap(long, help = "Optional model to use for the context")]
         model: Option<String>,
         #[clap(long, help = "Optional model to use for the context")]
-        serve: bool
+        serve: bool,
+        #[clap(long, help = "Redo every stage instead of resuming the last run")]
+        fresh: bool,
+        #[command(flatten)]
+        sampling: SamplingArgs,
+        #[command(flatten)]
+        valuation: ValuationArgs
     },
 
     #[clap(name = "make_ticker", about = "Generate a folder with required files and folders.")]
     MakeTicker{
         ticker: String
+    },
+
+    #[clap(name = "import", about = "Import statements from CSV, JSON or SEC XBRL files into a ticker folder.")]
+    Import {
+        ticker: String,
+        #[clap(required = true, help = "CSV or JSON statements, or XBRL (.xml) or inline XBRL (.htm) filings")]
+        files: Vec<std::path::PathBuf>,
+        #[clap(long, value_enum, help = "Which statement the CSV or JSON files hold, when their names don't say")]
+        statement: Option<StatementKind>
+    },
+
+    #[clap(name = "models", about = "List the models that can be passed to --model.")]
+    Models,
+
+    #[clap(name = "cache", about = "Manage the cache of model replies.")]
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommand
+    },
+
+    #[clap(name = "usage", about = "Report tokens used and money spent, from the local ledger.")]
+    Usage {
+        #[clap(long, help = "Only count calls on or after this day, as YYYY-MM-DD")]
+        since: Option<chrono::NaiveDate>,
+        #[clap(long, value_enum, default_value_t = GroupBy::Day, help = "What to total by")]
+        by: GroupBy
+    }
+
+
+}
+
+#[derive(Subcommand)]
+enum CacheCommand {
+    #[clap(name = "clear", about = "Delete cached replies.")]
+    Clear {
+        #[clap(long, help = "Only delete replies older than the cache TTL")]
+        expired: bool
+    }
+}
+
+/// Flags controlling how the model picks its words, for the commands that
+/// talk to one. Unset flags leave the provider's defaults alone.
+#[derive(clap::Args)]
+struct SamplingArgs {
+    #[clap(long, help = "Sampling temperature, lower is more deterministic")]
+    temperature: Option<f32>,
+    #[clap(long, help = "Only sample from the likeliest tokens making up this much probability")]
+    top_p: Option<f32>,
+    #[clap(long, help = "Seed for repeatable sampling, where the provider supports it")]
+    seed: Option<u64>,
+    #[clap(long, help = "Stop the reply at this text, can be repeated")]
+    stop: Vec<String>,
+    #[clap(long, help = "Penalty for tokens that already appeared, making new topics likelier")]
+    presence_penalty: Option<f32>,
+    #[clap(long, help = "Penalty for tokens by how often they already appeared")]
+    frequency_penalty: Option<f32>,
+    #[clap(long, help = "Longest reply to ask for, capped by the model's own limit")]
+    max_tokens: Option<i32>
+}
+
+impl SamplingArgs {
+    fn apply(&self, llm: &mut llm::LLM) {
+        llm.sampling = Sampling {
+            temperature: self.temperature,
+            top_p: self.top_p,
+            seed: self.seed,
+            stop: self.stop.clone(),
+            presence_penalty: self.presence_penalty,
+            frequency_penalty: self.frequency_penalty
+        };
+        if let Some(max_tokens) = self.max_tokens {
+            llm.max_tokens = max_tokens;
+        }
     }
+}
 
+/// Flags overriding the `[valuation]` assumptions of `finance`.
+#[derive(clap::Args)]
+struct ValuationArgs {
+    #[clap(long, help = "Discount rate of the DCF, e.g. 0.09 for 9%")]
+    wacc: Option<f64>,
+    #[clap(long, help = "Yearly free cash flow growth after the forecast, e.g. 0.025")]
+    terminal_growth: Option<f64>,
+    #[clap(long, help = "Yearly free cash flow growth during the forecast, instead of the historical growth")]
+    growth: Option<f64>,
+    #[clap(long, help = "Number of years forecast before the terminal value")]
+    years: Option<usize>
+}
+
+impl ValuationArgs {
+    fn apply(&self, config: &mut valuation::ValuationConfig) {
+        if let Some(wacc) = self.wacc {
+            config.wacc = wacc;
+        }
+        if let Some(terminal_growth) = self.terminal_growth {
+            config.terminal_growth = terminal_growth;
+        }
+        if let Some(growth) = self.growth {
+            config.growth = Some(growth);
+        }
+        if let Some(years) = self.years {
+            config.years = years;
+        }
+    }
 }
 
 
-fn main() -> Result<(), GenericError>{
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("ERROR: {}", e);
+        let code = e.downcast_ref::<LlmError>()
+            .map(|e| e.exit_code())
+            .unwrap_or(1);
+        std::process::exit(code);
+    }
+}
+
+fn run() -> Result<(), GenericError>{
     let cli = Cli::parse();
-    let mut llm = llm::LLM::new();
+    let mut config = Config::load(cli.config.as_ref())?;
+    if cli.no_cache {
+        config.cache.enabled = false;
+    }
+
+    let registry = ModelRegistry::new(&config.models);
 
     match &cli.command {
-        Some(Commands::Query {prompt, model}) => {
-            let model = match model{
-                Some(model_str) => {
-                    match model_str.as_str(){
-                        "L8" => llm::Model::LLMA8b,
-                        "L70" => llm::Model::LLMA70b,
-                        "M" => llm::Model::MISTRAL,
-                        "G7" => llm::Model::GEMMA7b,
-                        "G9" => llm::Model::GEMMA9b,
-                        _ => llm::Model::LLMA8b
-                    }
-                },
-                None => llm::Model::LLMA8b
+        Some(Commands::Query {prompt, file, model, json, schema, tools, max_tool_rounds, sampling}) => {
+            let (mut llm, model) = build_llm(&config, &registry, model.as_ref(), "L8")?;
+            sampling.apply(&mut llm);
+            llm.meter = usage_meter(&config, "query");
+
+            let mut query = prompt.clone().unwrap_or_default();
+
+            if let Some(piped) = read_piped_stdin()? {
+                query.push_str(&format!("\n\n```\n{}\n```", piped.trim_end()));
+            }
+
+            let mut images = Vec::new();
+
+            for path in file {
+                if let Some(image) = read_image(path)? {
+                    images.push(image);
+                    continue;
+                }
+                let content = read_attachment(path)?;
+                query.push_str(&format!("\n\nFile `{}`:\n```\n{}\n```", path.display(), content.trim_end()));
+            }
+
+            let schema: Option<serde_json::Value> = match schema {
+                Some(path) => Some(serde_json::from_str(&std::fs::read_to_string(path)?)
+                    .map_err(|e| format!("invalid JSON Schema {}: {}", path.display(), e))?),
+                None => None
             };
 
-            if prompt.len() != 0{
-                llm.prompt(Some("".to_string()), model, true)?;
+            if query.trim().is_empty() && images.is_empty(){
+                println!("No input provided, can't query the LLM");
+            }
+            else if !images.is_empty() && (*tools || *json || schema.is_some()) {
+                return Err("images can't be combined with --tools, --json or --schema".into());
+            }
+            else if *tools {
+                let answer = llm.prompt_with_tools(&query, &tools::filings(&config.data_root()), &model, *max_tool_rounds)?;
+                println!("{}", answer);
+            }
+            else if *json || schema.is_some() {
+                let answer = llm.prompt_json(&query, schema.as_ref(), &model)?;
+                println!("{}", serde_json::to_string_pretty(&answer)?);
             }
             else{
-                println!("No input provided, can't query the LLM");
+                let mut parts = vec![ContentPart::Text(query.trim().to_string())];
+                parts.extend(images);
+                llm.prompt_parts(parts, &model, true)?;
             }
         }
-        Some(Commands::Context { look_back , model, system}) => {
+        Some(Commands::Batch {input, output, concurrency, model, system}) => {
+            let batch = Batch {
+                config: &config,
+                registry: &registry,
+                model: model.clone(),
+                system: system.clone(),
+                concurrency: *concurrency,
+                meter: usage_meter(&config, "batch")
+            };
+            let result = batch.run(input, output);
+            print_usage(&batch.meter);
+            result?;
+        }
+        Some(Commands::Context { look_back , summarize, model, system, resume, list, sampling}) => {
+            let store = SessionStore::new(config.state_dir().join("sessions"));
+
+            if *list {
+                println!("{:<16} {:<26} {:>5}  {:<17} TITLE", "ID", "MODEL", "TURNS", "UPDATED");
+                for session in store.list()? {
+                    println!("{:<16} {:<26} {:>5}  {:<17} {}", session.id, session.model, session.turns.len(),
+                        session.updated_at.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M"), session.title());
+                }
+                return Ok(());
+            }
+
+            let resumed = match resume {
+                Some(id) => Some(store.load(id)?),
+                None => None
+            };
+
+            // Flags win over whatever the resumed session was started with
+            let model_name = model.clone().or(resumed.as_ref().map(|session| session.model.clone()));
+            let (mut llm, model) = build_llm(&config, &registry, model_name.as_ref(), "L8")?;
+            sampling.apply(&mut llm);
+            llm.meter = usage_meter(&config, "context");
+            // /retry has to get a new answer, not the one it is replacing
+            llm.cache = None;
+            let meter = llm.meter.clone();
+
             if system.is_some(){
                 llm.system = system.clone()
             }
-            else{
+            else if let Some(session) = &resumed {
+                llm.system = session.system.clone()
+            }
+            else if llm.system.is_none(){
                 llm.system = Some(String::from("I want concise answers, do not give me large swath of text."))
             }
-            let model = match model{
-                Some(model_str) => {
-                    match model_str.as_str(){
-                        "L8" => llm::Model::LLMA8b,
-                        "L70" => llm::Model::LLMA70b,
-                        "M" => llm::Model::MISTRAL,
-                        "G7" => llm::Model::GEMMA7b,
-                        "G9" => llm::Model::GEMMA9b,
-                        _ => llm::Model::LLMA8b
-                    }
-                },
-                None => llm::Model::LLMA8b
+
+            let mut session = match resumed {
+                Some(session) => {
+                    println!("Resuming conversation {} ({} turns)", session.id, session.turns.len());
+                    session
+                }
+                None => Session::new(&model.id, llm.system.clone())
             };
+            session.model = model.id.clone();
+            session.system = llm.system.clone();
 
-            if look_back.is_some() && look_back.unwrap() >= 1{
-                llm.context_prompt(look_back.unwrap() as usize, model)?;
-            }
-            else{
-                llm.context_prompt(20, model)?;
-            }
-        }
-        Some(Commands::Finance {model, ticker, serve}) => {
-            let model = match model{
-                Some(model_str) => {
-                    match model_str.as_str(){
-                        "L8" => llm::Model::LLMA8b,
-                        "L70" => llm::Model::LLMA70b,
-                        "M" => llm::Model::MISTRAL,
-                        "G7" => llm::Model::GEMMA7b,
-                        "G9" => llm::Model::GEMMA9b,
-                        _ => llm::Model::LLMA8b
-                    }
-                },
-                None => llm::Model::LLMA8b
+            let look_back = look_back.filter(|look_back| *look_back >= 0).map(|look_back| look_back as usize);
+
+            let mut repl = Repl {
+                llm,
+                model,
+                session,
+                look_back,
+                summarize: *summarize,
+                store,
+                history: config.state_dir().join("history.txt"),
+                config: &config,
+                registry: &registry
             };
+            let result = repl.run();
+            print_usage(&meter);
+            result?;
+        }
+        Some(Commands::Finance {model, ticker, serve, fresh, sampling, valuation}) => {
+            let (mut llm, model) = build_llm(&config, &registry, model.as_ref(), "L70")?;
+            sampling.apply(&mut llm);
+            llm.meter = usage_meter(&config, "finance");
+            let meter = llm.meter.clone();
 
-            llm.model = Some(model);
-            let mut fin = Finance::new(ticker.to_string(), llm);
-            fin.run()?;
+            let mut fin = Finance::new(ticker.to_string(), llm, model, config.data_root());
+            fin.valuation = config.valuation.clone();
+            valuation.apply(&mut fin.valuation);
+            let result = fin.run(*fresh);
+            print_usage(&meter);
+            result?;
 
             if *serve {
-                serve_files(&format!("/Users/mmuhammad/Documents/financials/{}/analysis", ticker));
+                serve_files(&format!("{}/analysis", config.ticker_dir(ticker)));
             }
 
         }
         Some(Commands::MakeTicker {ticker}) => {
-            let path = format!("/Users/mmuhammad/Documents/financials/{}",ticker);
+            let path = config.ticker_dir(ticker);
 
             if !std::path::Path::new(&path).exists() {
-                std::fs::create_dir(&path)?;
+                std::fs::create_dir_all(&path)?;
             }
             std::fs::File::create(format!("{}/income_statement.txt",path))?;
             std::fs::File::create(format!("{}/balance_sheet_statement.txt",path))?;
@@ -168,9 +444,78 @@ fn main() -> Result<(), GenericError>{
 
         }
 
+        Some(Commands::Import {ticker, files, statement}) => {
+            let path = config.ticker_dir(ticker);
+            std::fs::create_dir_all(&path)?;
+
+            for file in files {
+                let imported = import::import(file, *statement)?;
+                if imported.is_empty() {
+                    eprintln!("WARNING: found no {} in {}", statement.map_or("statements", |kind| kind.name()), file.display());
+                }
+                for (kind, imported) in imported {
+                    let output = format!("{}/{}.json", path, kind.file_stem());
+                    std::fs::write(&output, serde_json::to_string_pretty(&imported)?)?;
+                    println!("Imported the {} from {}: {} line items for {}, written to {}", kind.name(), file.display(), imported.line_items.len(), imported.periods.join(", "), output);
+                }
+            }
+        }
+
+        Some(Commands::Models) => {
+            println!("{:<30} {:<10} {:>8} {:>8} {:>12}  ALIASES", "ID", "PROVIDER", "CONTEXT", "OUTPUT", "$/M IN/OUT");
+            for model in registry.models() {
+                let price = match (model.input_price, model.output_price) {
+                    (Some(input), Some(output)) => format!("{}/{}", input, output),
+                    _ => "-".to_string()
+                };
+                println!("{:<30} {:<10} {:>8} {:>8} {:>12}  {}", model.id, model.provider.name(), model.context_window, model.max_output_tokens, price, model.aliases.join(", "));
+            }
+        }
+
+        Some(Commands::Cache {command: CacheCommand::Clear {expired}}) => {
+            let removed = config.response_cache().clear(*expired)?;
+            println!("Deleted {} cached replies", removed);
+        }
+
+        Some(Commands::Usage {since, by}) => {
+            let entries = usage::read_ledger(&config.usage_ledger())?;
+            print!("{}", usage::report(&entries, *since, *by));
+        }
+
         None => {}
     }
 
 
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Resolves `--model` (falling back to the config, then `default`) and builds
+/// an `LLM` talking to that model's provider.
+fn build_llm(config: &Config, registry: &ModelRegistry, model: Option<&String>, default: &str) -> Result<(llm::LLM, ModelInfo), GenericError> {
+    let name = model.or(config.model.as_ref()).map(|name| name.as_str()).unwrap_or(default);
+    let model = registry.resolve(name)?;
+
+    let mut llm = llm::LLM::new(backend::from_provider(model.provider, &config.provider(model.provider.name())));
+    llm.max_tokens = config.max_tokens();
+    llm.system = config.system_prompt.clone();
+    llm.decoration = config.decoration.clone();
+    if config.cache.enabled {
+        llm.cache = Some(config.response_cache());
+    }
+
+    if llm.decoration.prefill.is_some() && !llm.backend.supports_prefill() {
+        eprintln!("WARNING: {} does not support prefill, ignoring it", llm.backend.name());
+    }
+
+    Ok((llm, model))
+}
+
+fn usage_meter(config: &Config, command: &str) -> Arc<UsageMeter> {
+    Arc::new(UsageMeter::new(command, config.usage_ledger()))
+}
+
+fn print_usage(meter: &UsageMeter) {
+    if let Some(summary) = meter.summary() {
+        eprintln!("\n{}", summary.trim_end());
+    }
+}
@@ -0,0 +1,176 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::summary::Checkpoint;
+use crate::GenericError;
+
+/// What a `finance` run has finished so far, kept as `manifest.json` in the
+/// ticker's `analysis/` folder. A stage is done once its output is written,
+/// and stays done until one of its input files or the model changes.
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct Manifest {
+    #[serde(skip)]
+    path: PathBuf,
+    pub stages: BTreeMap<String, Stage>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Stage {
+    pub model: String,
+    /// SHA-256 of every input file, by file name.
+    pub inputs: BTreeMap<String, String>,
+    pub completed_at: Option<DateTime<Utc>>,
+    /// Chunks of a report already summarized, so an interrupted report picks
+    /// up at the next chunk.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chunks: Vec<Checkpoint>
+}
+
+impl Stage {
+    /// A stage not done yet, reading `inputs` with `model`.
+    pub fn new(model: &str, inputs: &[&Path]) -> Result<Self, GenericError> {
+        let mut hashes = BTreeMap::new();
+        for input in inputs {
+            let name = input.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+            hashes.insert(name, hash_file(input)?);
+        }
+
+        Ok(Stage { model: model.to_string(), inputs: hashes, completed_at: None, chunks: Vec::new() })
+    }
+
+    fn same_work(&self, other: &Stage) -> bool {
+        self.model == other.model && self.inputs == other.inputs
+    }
+}
+
+impl Manifest {
+    /// Reads the manifest at `path`. A missing or unreadable one means
+    /// starting from scratch.
+    pub fn load(path: PathBuf) -> Self {
+        let manifest = std::fs::read_to_string(&path).ok().map(|text| serde_json::from_str::<Manifest>(&text));
+
+        match manifest {
+            Some(Ok(manifest)) => Manifest { path, ..manifest },
+            Some(Err(e)) => {
+                eprintln!("WARNING: ignoring the unreadable run manifest {}: {}", path.display(), e);
+                Manifest { path, ..Manifest::default() }
+            }
+            None => Manifest { path, ..Manifest::default() }
+        }
+    }
+
+    /// An empty manifest at `path`, for redoing every stage.
+    pub fn fresh(path: PathBuf) -> Self {
+        Manifest { path, ..Manifest::default() }
+    }
+
+    /// Writes the manifest next to its final place first, so a run killed
+    /// halfway through a save can't leave a broken one behind.
+    pub fn save(&self) -> Result<(), GenericError> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let temporary = self.path.with_extension("json.tmp");
+        std::fs::write(&temporary, serde_json::to_string_pretty(self)?)?;
+        std::fs::rename(&temporary, &self.path)?;
+        Ok(())
+    }
+
+    /// Whether `name` already finished doing the same work as `stage`.
+    pub fn is_done(&self, name: &str, stage: &Stage) -> bool {
+        self.stages.get(name).is_some_and(|done| done.completed_at.is_some() && done.same_work(stage))
+    }
+
+    /// Marks `name` as started. Chunks checkpointed by an earlier attempt at
+    /// the same work are kept, anything else about the stage is dropped.
+    pub fn start(&mut self, name: &str, stage: Stage) {
+        let chunks = match self.stages.get(name) {
+            Some(earlier) if earlier.same_work(&stage) => earlier.chunks.clone(),
+            _ => Vec::new()
+        };
+        self.stages.insert(name.to_string(), Stage { chunks, ..stage });
+    }
+
+    pub fn complete(&mut self, name: &str) {
+        if let Some(stage) = self.stages.get_mut(name) {
+            stage.completed_at = Some(Utc::now());
+        }
+    }
+
+    /// Forgets `name`, for a stage whose output was removed.
+    pub fn remove(&mut self, name: &str) {
+        self.stages.remove(name);
+    }
+
+    pub fn chunks(&self, name: &str) -> &[Checkpoint] {
+        self.stages.get(name).map(|stage| stage.chunks.as_slice()).unwrap_or_default()
+    }
+
+    pub fn add_chunk(&mut self, name: &str, chunk: Checkpoint) {
+        if let Some(stage) = self.stages.get_mut(name) {
+            stage.chunks.push(chunk);
+        }
+    }
+}
+
+fn hash_file(path: &Path) -> Result<String, GenericError> {
+    let content = std::fs::read(path)
+        .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+    Ok(format!("{:x}", Sha256::digest(content)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::summary::Summary;
+
+    #[test]
+    fn stages_rerun_when_inputs_or_model_change() {
+        let dir = std::env::temp_dir().join(format!("llm_search_manifest_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("income_statement.txt");
+        std::fs::write(&input, "Revenue 100").unwrap();
+
+        let mut manifest = Manifest::load(dir.join("manifest.json"));
+        let stage = Stage::new("llama3-70b-8192", &[&input]).unwrap();
+        assert!(!manifest.is_done("income_analysis", &stage));
+
+        manifest.start("income_analysis", stage.clone());
+        manifest.complete("income_analysis");
+        manifest.save().unwrap();
+
+        let manifest = Manifest::load(dir.join("manifest.json"));
+        assert!(manifest.is_done("income_analysis", &stage));
+        assert!(!manifest.is_done("income_analysis", &Stage::new("gpt-4o", &[&input]).unwrap()));
+
+        std::fs::write(&input, "Revenue 120").unwrap();
+        assert!(!manifest.is_done("income_analysis", &Stage::new("llama3-70b-8192", &[&input]).unwrap()));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn restarting_the_same_work_keeps_its_chunks() {
+        let dir = std::env::temp_dir().join(format!("llm_search_manifest_chunks_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let report = dir.join("10-K.pdf");
+        std::fs::write(&report, "%PDF").unwrap();
+
+        let summary = Summary { first_page: 1, last_page: 4, text: "Revenue grew (p. 2).".to_string() };
+        let chunk = Checkpoint { index: 0, hash: "ab12".to_string(), summary };
+        let mut manifest = Manifest::fresh(dir.join("manifest.json"));
+
+        manifest.start("report:10-K.pdf", Stage::new("gpt-4o", &[&report]).unwrap());
+        manifest.add_chunk("report:10-K.pdf", chunk.clone());
+        manifest.start("report:10-K.pdf", Stage::new("gpt-4o", &[&report]).unwrap());
+        assert_eq!(manifest.chunks("report:10-K.pdf"), [chunk]);
+
+        manifest.start("report:10-K.pdf", Stage::new("gpt-4o-mini", &[&report]).unwrap());
+        assert!(manifest.chunks("report:10-K.pdf").is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,115 @@
+use serde::Deserialize;
+
+use crate::backend::{Provider, Usage};
+use crate::error::LlmError;
+
+/// A model the tool knows how to talk to. Built-in entries can be extended or
+/// overridden with `[[models]]` tables in the config file.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ModelInfo {
+    pub id: String,
+    pub provider: Provider,
+    pub context_window: u32,
+    pub max_output_tokens: u32,
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    /// USD per million prompt tokens.
+    #[serde(default)]
+    pub input_price: Option<f64>,
+    /// USD per million completion tokens.
+    #[serde(default)]
+    pub output_price: Option<f64>
+}
+
+impl ModelInfo {
+    fn new(id: &str, provider: Provider, context_window: u32, max_output_tokens: u32, aliases: &[&str]) -> Self {
+        ModelInfo {
+            id: id.to_string(),
+            provider,
+            context_window,
+            max_output_tokens,
+            aliases: aliases.iter().map(|alias| alias.to_string()).collect(),
+            input_price: None,
+            output_price: None
+        }
+    }
+
+    fn priced(mut self, input_price: f64, output_price: f64) -> Self {
+        self.input_price = Some(input_price);
+        self.output_price = Some(output_price);
+        self
+    }
+
+    /// What `usage` cost in USD, `None` if the model has no prices. Local
+    /// Ollama models are free.
+    pub fn cost(&self, usage: &Usage) -> Option<f64> {
+        if self.provider == Provider::Ollama && self.input_price.is_none() && self.output_price.is_none() {
+            return Some(0.0);
+        }
+        let input = self.input_price? * usage.prompt_tokens as f64;
+        let output = self.output_price? * usage.completion_tokens as f64;
+        Some((input + output) / 1_000_000.0)
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        self.id == name || self.aliases.iter().any(|alias| alias.eq_ignore_ascii_case(name))
+    }
+}
+
+fn builtin_models() -> Vec<ModelInfo> {
+    vec![
+        ModelInfo::new("llama3-8b-8192", Provider::Groq, 8192, 8192, &["L8"]).priced(0.05, 0.08),
+        ModelInfo::new("llama3-70b-8192", Provider::Groq, 8192, 8192, &["L70"]).priced(0.59, 0.79),
+        ModelInfo::new("llama-3.1-8b-instant", Provider::Groq, 131072, 8192, &["L31-8"]).priced(0.05, 0.08),
+        ModelInfo::new("llama-3.1-70b-versatile", Provider::Groq, 131072, 8000, &["L31-70"]).priced(0.59, 0.79),
+        ModelInfo::new("mixtral-8x7b-32768", Provider::Groq, 32768, 32768, &["M"]).priced(0.24, 0.24),
+        ModelInfo::new("gemma-7b-it", Provider::Groq, 8192, 8192, &["G7"]).priced(0.07, 0.07),
+        ModelInfo::new("gemma2-9b-it", Provider::Groq, 8192, 8192, &["G9"]).priced(0.20, 0.20),
+        ModelInfo::new("gpt-4o", Provider::Openai, 128000, 16384, &["4o"]).priced(2.50, 10.00),
+        ModelInfo::new("gpt-4o-mini", Provider::Openai, 128000, 16384, &["4o-mini"]).priced(0.15, 0.60),
+        ModelInfo::new("claude-3-5-sonnet-20240620", Provider::Anthropic, 200000, 8192, &["sonnet"]).priced(3.00, 15.00),
+        ModelInfo::new("claude-3-haiku-20240307", Provider::Anthropic, 200000, 4096, &["haiku"]).priced(0.25, 1.25),
+    ]
+}
+
+// Used for `provider:model-id` names that are not in the registry.
+const ADHOC_CONTEXT_WINDOW: u32 = 8192;
+const ADHOC_MAX_OUTPUT_TOKENS: u32 = 4096;
+
+pub struct ModelRegistry {
+    models: Vec<ModelInfo>
+}
+
+impl ModelRegistry {
+    /// Built-in models plus the ones from the config. A config entry with the
+    /// same ID as a built-in one replaces it.
+    pub fn new(configured: &[ModelInfo]) -> Self {
+        let mut models: Vec<ModelInfo> = builtin_models().into_iter()
+            .filter(|builtin| !configured.iter().any(|model| model.id == builtin.id))
+            .collect();
+        models.extend(configured.iter().cloned());
+
+        ModelRegistry { models }
+    }
+
+    pub fn models(&self) -> &[ModelInfo] {
+        &self.models
+    }
+
+    /// Looks a model up by ID or alias. `provider:model-id` (e.g.
+    /// `ollama:llama3.1`) names a model the registry doesn't know about.
+    pub fn resolve(&self, name: &str) -> Result<ModelInfo, LlmError> {
+        if let Some(model) = self.models.iter().find(|model| model.matches(name)) {
+            return Ok(model.clone());
+        }
+
+        if let Some((provider, id)) = name.split_once(':') {
+            if let Ok(provider) = clap::ValueEnum::from_str(provider, true) {
+                return Ok(ModelInfo::new(id, provider, ADHOC_CONTEXT_WINDOW, ADHOC_MAX_OUTPUT_TOKENS, &[]));
+            }
+        }
+
+        Err(LlmError::UnknownModel(name.to_string()))
+    }
+}
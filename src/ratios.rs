@@ -0,0 +1,294 @@
+use serde::Serialize;
+
+use crate::statements::FinancialStatement;
+
+pub const REVENUE: &[&str] = &["Total Revenue", "Revenue", "Revenues", "Total Revenues", "Net Sales", "Total Net Sales", "Net Revenue", "Net Revenues", "Sales"];
+const COST_OF_REVENUE: &[&str] = &["Cost of Revenue", "Total Cost of Revenue", "Cost of Goods Sold", "Cost of Sales", "Cost of Goods and Services Sold"];
+const GROSS_PROFIT: &[&str] = &["Gross Profit", "Gross Margin"];
+const OPERATING_INCOME: &[&str] = &["Operating Income", "Income from Operations", "Operating Profit", "Total Operating Income as Reported"];
+const PRETAX_INCOME: &[&str] = &["Pretax Income", "Income Before Income Taxes", "Income Before Taxes", "Income Before Provision for Income Taxes"];
+const INCOME_TAX: &[&str] = &["Tax Provision", "Income Tax Expense", "Provision for Income Taxes", "Income Taxes"];
+const INTEREST_EXPENSE: &[&str] = &["Interest Expense", "Interest Expense Non Operating"];
+const NET_INCOME: &[&str] = &["Net Income", "Net Income Common Stockholders", "Net Income Attributable to Common Shareholders", "Net Earnings", "Net Income (Loss)"];
+
+const TOTAL_ASSETS: &[&str] = &["Total Assets"];
+const TOTAL_LIABILITIES: &[&str] = &["Total Liabilities", "Total Liabilities Net Minority Interest"];
+const TOTAL_EQUITY: &[&str] = &["Total Equity", "Stockholders Equity", "Total Stockholders Equity", "Total Shareholders Equity", "Shareholders Equity", "Common Stock Equity", "Total Equity Gross Minority Interest"];
+const CURRENT_ASSETS: &[&str] = &["Total Current Assets", "Current Assets"];
+const CURRENT_LIABILITIES: &[&str] = &["Total Current Liabilities", "Current Liabilities"];
+const INVENTORY: &[&str] = &["Inventory", "Inventories"];
+pub const CASH: &[&str] = &["Cash and Cash Equivalents", "Cash, Cash Equivalents and Short Term Investments", "Cash"];
+const TOTAL_DEBT: &[&str] = &["Total Debt"];
+const LONG_TERM_DEBT: &[&str] = &["Long Term Debt", "Long-Term Debt"];
+const SHORT_TERM_DEBT: &[&str] = &["Current Debt", "Short Term Debt", "Short-Term Debt", "Current Portion of Long Term Debt"];
+
+const OPERATING_CASH_FLOW: &[&str] = &["Operating Cash Flow", "Cash Flow from Operating Activities", "Cash Flows from Operating Activities", "Net Cash Provided by Operating Activities", "Net Cash Provided by (Used in) Operating Activities", "Net Cash from Operating Activities", "Cash from Operations"];
+const CAPITAL_EXPENDITURE: &[&str] = &["Capital Expenditure", "Capital Expenditures", "Purchases of Property and Equipment", "Purchase of Property, Plant and Equipment", "Payments for Acquisition of Property, Plant and Equipment"];
+const FREE_CASH_FLOW: &[&str] = &["Free Cash Flow"];
+
+/// Figures and ratios for one period. Amounts are in units of the
+/// statement's currency, ratios are fractions (0.25 is 25%), and anything
+/// the statements don't have the figures for is left out.
+#[derive(Serialize, Default, Clone, Debug, PartialEq)]
+pub struct PeriodRatios {
+    pub period: String,
+    pub revenue: Option<f64>,
+    pub gross_profit: Option<f64>,
+    pub operating_income: Option<f64>,
+    pub net_income: Option<f64>,
+    pub free_cash_flow: Option<f64>,
+    pub gross_margin: Option<f64>,
+    pub operating_margin: Option<f64>,
+    pub net_margin: Option<f64>,
+    pub free_cash_flow_margin: Option<f64>,
+    /// Against the period before.
+    pub revenue_growth: Option<f64>,
+    pub net_income_growth: Option<f64>,
+    /// Net income over period-end equity.
+    pub return_on_equity: Option<f64>,
+    /// Operating income after tax over debt plus equity minus cash.
+    pub return_on_invested_capital: Option<f64>,
+    pub current_ratio: Option<f64>,
+    pub quick_ratio: Option<f64>,
+    pub debt_to_equity: Option<f64>,
+    pub liabilities_to_assets: Option<f64>,
+    pub interest_coverage: Option<f64>
+}
+
+/// Ratios computed from the parsed statements, written to `ratios.json`
+/// and handed to the model as figures it doesn't have to work out itself.
+#[derive(Serialize, Default, Clone, Debug, PartialEq)]
+pub struct Ratios {
+    pub currency: Option<String>,
+    pub periods: Vec<PeriodRatios>
+}
+
+/// Works out the ratios for every period of the first statement given. The
+/// balance sheet and cash flow periods are matched to it by label or year.
+pub fn compute(income: Option<&FinancialStatement>, balance_sheet: Option<&FinancialStatement>, cash_flow: Option<&FinancialStatement>) -> Ratios {
+    let Some(base) = income.or(balance_sheet).or(cash_flow) else { return Ratios::default() };
+
+    let periods = base.periods.iter().enumerate().map(|(column, period)| {
+        let column_in = |statement: Option<&FinancialStatement>| -> Option<usize> {
+            let statement = statement?;
+            if std::ptr::eq(statement, base) { Some(column) } else { statement.column(period) }
+        };
+        // Figure `names` from `statement` in this period, or the one before
+        let value = |statement: Option<&FinancialStatement>, names: &[&str], previous: bool| -> Option<f64> {
+            let mut column = column_in(statement)?;
+            if previous {
+                column = statement?.previous(column)?;
+            }
+            statement?.value(names, column)
+        };
+
+        let revenue = value(income, REVENUE, false);
+        let gross_profit = value(income, GROSS_PROFIT, false)
+            .or_else(|| Some(revenue? - value(income, COST_OF_REVENUE, false)?.abs()));
+        let operating_income = value(income, OPERATING_INCOME, false);
+        let net_income = value(income, NET_INCOME, false);
+
+        let free_cash_flow = cash_flow.zip(column_in(cash_flow)).and_then(|(cash_flow, column)| free_cash_flow(cash_flow, column));
+
+        let equity = value(balance_sheet, TOTAL_EQUITY, false);
+        let debt = balance_sheet.zip(column_in(balance_sheet)).and_then(|(balance_sheet, column)| total_debt(balance_sheet, column));
+        let current_assets = value(balance_sheet, CURRENT_ASSETS, false);
+        let current_liabilities = value(balance_sheet, CURRENT_LIABILITIES, false);
+
+        let tax_rate = divide(value(income, INCOME_TAX, false), value(income, PRETAX_INCOME, false))
+            .map(|rate| rate.clamp(0.0, 1.0));
+        let after_tax = operating_income.zip(tax_rate).map(|(income, rate)| income * (1.0 - rate));
+        let cash = value(balance_sheet, CASH, false).unwrap_or(0.0);
+        let invested_capital = debt.zip(equity).map(|(debt, equity)| debt + equity - cash);
+        let inventory = value(balance_sheet, INVENTORY, false).unwrap_or(0.0);
+
+        PeriodRatios {
+            period: period.clone(),
+            revenue,
+            gross_profit,
+            operating_income,
+            net_income,
+            free_cash_flow,
+            gross_margin: divide(gross_profit, revenue),
+            operating_margin: divide(operating_income, revenue),
+            net_margin: divide(net_income, revenue),
+            free_cash_flow_margin: divide(free_cash_flow, revenue),
+            revenue_growth: growth(revenue, value(income, REVENUE, true)),
+            net_income_growth: growth(net_income, value(income, NET_INCOME, true)),
+            return_on_equity: divide(net_income, equity),
+            return_on_invested_capital: divide(after_tax, invested_capital),
+            current_ratio: divide(current_assets, current_liabilities),
+            quick_ratio: divide(current_assets.map(|assets| assets - inventory), current_liabilities),
+            debt_to_equity: divide(debt, equity),
+            liabilities_to_assets: divide(value(balance_sheet, TOTAL_LIABILITIES, false), value(balance_sheet, TOTAL_ASSETS, false)),
+            interest_coverage: divide(operating_income, value(income, INTEREST_EXPENSE, false).map(f64::abs))
+        }
+    }).collect();
+
+    Ratios { currency: base.currency.clone(), periods }
+}
+
+/// Free cash flow in `column` of a cash flow statement: as reported, or
+/// operating cash flow less capital expenditure.
+pub fn free_cash_flow(cash_flow: &FinancialStatement, column: usize) -> Option<f64> {
+    cash_flow.value(FREE_CASH_FLOW, column).or_else(|| {
+        Some(cash_flow.value(OPERATING_CASH_FLOW, column)? - cash_flow.value(CAPITAL_EXPENDITURE, column)?.abs())
+    })
+}
+
+/// Debt in `column` of a balance sheet: as reported, or long term plus
+/// current debt.
+pub fn total_debt(balance_sheet: &FinancialStatement, column: usize) -> Option<f64> {
+    balance_sheet.value(TOTAL_DEBT, column).or_else(|| {
+        let long = balance_sheet.value(LONG_TERM_DEBT, column);
+        let short = balance_sheet.value(SHORT_TERM_DEBT, column);
+        if long.is_none() && short.is_none() { None } else { Some(long.unwrap_or(0.0) + short.unwrap_or(0.0)) }
+    })
+}
+
+impl Ratios {
+    /// The figures as lines for a prompt, amounts spelled out in millions or
+    /// billions so they can't be mixed up.
+    pub fn to_prompt(&self) -> String {
+        let mut lines = Vec::new();
+        let currency = self.currency.as_deref().unwrap_or("");
+
+        for period in &self.periods {
+            let mut figures = Vec::new();
+            let mut amount = |name: &str, value: Option<f64>| {
+                if let Some(value) = value {
+                    figures.push(format!("{} {}", name, spell_out(value, currency)));
+                }
+            };
+            amount("revenue", period.revenue);
+            amount("gross profit", period.gross_profit);
+            amount("operating income", period.operating_income);
+            amount("net income", period.net_income);
+            amount("free cash flow", period.free_cash_flow);
+
+            let ratios = [
+                ("gross margin", period.gross_margin, true),
+                ("operating margin", period.operating_margin, true),
+                ("net margin", period.net_margin, true),
+                ("free cash flow margin", period.free_cash_flow_margin, true),
+                ("revenue growth", period.revenue_growth, true),
+                ("net income growth", period.net_income_growth, true),
+                ("ROE", period.return_on_equity, true),
+                ("ROIC", period.return_on_invested_capital, true),
+                ("current ratio", period.current_ratio, false),
+                ("quick ratio", period.quick_ratio, false),
+                ("debt to equity", period.debt_to_equity, false),
+                ("liabilities to assets", period.liabilities_to_assets, false),
+                ("interest coverage", period.interest_coverage, false)
+            ];
+            for (name, value, percent) in ratios {
+                match value {
+                    Some(value) if percent => figures.push(format!("{} {:.1}%", name, value * 100.0)),
+                    Some(value) => figures.push(format!("{} {:.2}", name, value)),
+                    None => {}
+                }
+            }
+
+            if !figures.is_empty() {
+                lines.push(format!("- {}: {}", period.period, figures.join(", ")));
+            }
+        }
+
+        lines.join("\n")
+    }
+}
+
+fn divide(numerator: Option<f64>, denominator: Option<f64>) -> Option<f64> {
+    match (numerator, denominator) {
+        (Some(numerator), Some(denominator)) if denominator != 0.0 => Some(numerator / denominator),
+        _ => None
+    }
+}
+
+fn growth(current: Option<f64>, previous: Option<f64>) -> Option<f64> {
+    divide(Some(current? - previous?), previous.map(f64::abs))
+}
+
+/// `amount` in billions, millions or thousands of `currency`.
+pub fn spell_out(amount: f64, currency: &str) -> String {
+    let (value, unit) = match amount.abs() {
+        a if a >= 1e9 => (amount / 1e9, " billion"),
+        a if a >= 1e6 => (amount / 1e6, " million"),
+        a if a >= 1e3 => (amount / 1e3, " thousand"),
+        _ => (amount, "")
+    };
+    format!("{:.2}{} {}", value, unit, currency).trim_end().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::statements::parse;
+
+    #[test]
+    fn computes_ratios_across_statements() {
+        let income = parse("\
+In millions of USD
+Breakdown\tFY2023\tFY2022
+Total Revenue\t1,000\t800
+Cost of Revenue\t600\t500
+Operating Income\t200\t150
+Pretax Income\t180\t140
+Tax Provision\t36\t28
+Interest Expense\t(20)\t(20)
+Net Income\t144\t112
+").unwrap();
+        let balance_sheet = parse("\
+In millions of USD
+Breakdown\t12/31/2023\t12/31/2022
+Total Assets\t2,000\t1,800
+Total Liabilities Net Minority Interest\t1,200\t1,100
+Stockholders Equity\t800\t700
+Total Current Assets\t500\t400
+Total Current Liabilities\t250\t250
+Inventory\t100\t80
+Cash and Cash Equivalents\t100\t90
+Total Debt\t300\t320
+").unwrap();
+        let cash_flow = parse("\
+In millions of USD
+Breakdown\tFY2023\tFY2022
+Operating Cash Flow\t250\t200
+Capital Expenditure\t(50)\t(40)
+").unwrap();
+
+        let ratios = compute(Some(&income), Some(&balance_sheet), Some(&cash_flow));
+        let latest = &ratios.periods[0];
+        let close = |value: Option<f64>, expected: f64| (value.unwrap() - expected).abs() < 1e-9;
+
+        assert_eq!(ratios.currency.as_deref(), Some("USD"));
+        assert_eq!(latest.period, "FY2023");
+        assert!(close(latest.revenue, 1_000e6));
+        assert!(close(latest.gross_margin, 0.4));
+        assert!(close(latest.net_margin, 0.144));
+        assert!(close(latest.revenue_growth, 0.25));
+        assert!(close(latest.free_cash_flow, 200e6));
+        assert!(close(latest.return_on_equity, 0.18));
+        // 200 * (1 - 0.2) / (300 + 800 - 100)
+        assert!(close(latest.return_on_invested_capital, 0.16));
+        assert!(close(latest.current_ratio, 2.0));
+        assert!(close(latest.quick_ratio, 1.6));
+        assert!(close(latest.interest_coverage, 10.0));
+        assert_eq!(ratios.periods[1].revenue_growth, None);
+
+        let prompt = ratios.to_prompt();
+        assert!(prompt.starts_with("- FY2023: revenue 1.00 billion USD, gross profit 400.00 million USD,"));
+        assert!(prompt.contains("revenue growth 25.0%"));
+    }
+
+    #[test]
+    fn missing_statements_leave_ratios_out() {
+        let income = parse("Breakdown\tFY2023\nTotal Revenue\t1,000\nNet Income\t100\n").unwrap();
+
+        let ratios = compute(Some(&income), None, None);
+
+        assert_eq!(ratios.periods[0].net_margin, Some(0.1));
+        assert_eq!(ratios.periods[0].return_on_equity, None);
+        assert_eq!(compute(None, None, None), Ratios::default());
+    }
+}
@@ -0,0 +1,261 @@
+use std::path::PathBuf;
+use rustyline::error::ReadlineError;
+use rustyline::{Cmd, DefaultEditor, EventHandler, KeyCode, KeyEvent, Modifiers};
+
+use crate::backend;
+use crate::config::Config;
+use crate::llm::LLM;
+use crate::models::{ModelInfo, ModelRegistry};
+use crate::session::{Session, SessionStore};
+use crate::GenericError;
+
+const HELP: &str = r#"
+/model [name]      show or switch the model
+/system [prompt]   show or replace the system prompt
+/clear             start a new conversation, the current one stays saved
+/save [id]         save the conversation, optionally under a new id
+/load <id>         switch to a saved conversation
+/undo              forget the last question and answer
+/retry             ask the last question again
+/export <path>     write the conversation to a Markdown file
+/tokens            show how much of the context window is in use
+/quit              leave
+"#;
+
+const PROMPT: &str = "Please enter some input: ";
+const CONTINUATION: &str = "... ";
+// A line holding only this opens and closes a multiline block
+const MULTILINE: &str = "\"\"\"";
+
+/// Commands typed at the `context` prompt instead of a question.
+#[derive(Debug, PartialEq)]
+pub enum SlashCommand {
+    Model(Option<String>),
+    System(Option<String>),
+    Clear,
+    Save(Option<String>),
+    Load(String),
+    Undo,
+    Retry,
+    Export(String),
+    Tokens,
+    Help,
+    Quit
+}
+
+impl SlashCommand {
+    /// `None` if `input` isn't a slash command at all, `Some(Err)` if it is
+    /// one but can't be understood.
+    pub fn parse(input: &str) -> Option<Result<SlashCommand, String>> {
+        let input = input.trim();
+        let rest = input.strip_prefix('/')?;
+
+        let (name, argument) = match rest.split_once(char::is_whitespace) {
+            Some((name, argument)) => (name, Some(argument.trim().to_string()).filter(|a| !a.is_empty())),
+            None => (rest, None)
+        };
+
+        let required = |argument: Option<String>, usage: &str| argument.ok_or(format!("usage: {}", usage));
+
+        let command = match name {
+            "model" => Ok(SlashCommand::Model(argument)),
+            "system" => Ok(SlashCommand::System(argument)),
+            "clear" => Ok(SlashCommand::Clear),
+            "save" => Ok(SlashCommand::Save(argument)),
+            "load" => required(argument, "/load <id>").map(SlashCommand::Load),
+            "undo" => Ok(SlashCommand::Undo),
+            "retry" => Ok(SlashCommand::Retry),
+            "export" => required(argument, "/export <path>").map(SlashCommand::Export),
+            "tokens" => Ok(SlashCommand::Tokens),
+            "help" => Ok(SlashCommand::Help),
+            "quit" | "exit" => Ok(SlashCommand::Quit),
+            _ => Err(format!("unknown command /{}, try /help", name))
+        };
+
+        Some(command)
+    }
+}
+
+/// The interactive `context` session.
+pub struct Repl<'a> {
+    pub llm: LLM,
+    pub model: ModelInfo,
+    pub session: Session,
+    pub look_back: Option<usize>,
+    pub summarize: bool,
+    pub store: SessionStore,
+    pub history: PathBuf,
+    pub config: &'a Config,
+    pub registry: &'a ModelRegistry
+}
+
+impl Repl<'_> {
+    pub fn run(&mut self) -> Result<(), GenericError> {
+        let mut editor = DefaultEditor::new()?;
+        // Alt-Enter inserts a line break instead of sending the prompt
+        editor.bind_sequence(KeyEvent(KeyCode::Enter, Modifiers::ALT), EventHandler::Simple(Cmd::Newline));
+
+        if self.history.exists() {
+            editor.load_history(&self.history)?;
+        } else if let Some(dir) = self.history.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        let result = self.read_loop(&mut editor);
+        editor.save_history(&self.history)?;
+        result
+    }
+
+    fn read_loop(&mut self, editor: &mut DefaultEditor) -> Result<(), GenericError> {
+        loop {
+            let input = match read_input(editor)? {
+                Some(input) => input.trim_end().to_string(),
+                None => return Ok(())
+            };
+
+            if input.trim().is_empty() {
+                continue;
+            }
+
+            editor.add_history_entry(input.as_str())?;
+
+            match SlashCommand::parse(&input) {
+                Some(Ok(SlashCommand::Quit)) => return Ok(()),
+                Some(Ok(command)) => {
+                    if let Err(e) = self.handle(command) {
+                        eprintln!("ERROR: {}", e);
+                    }
+                }
+                Some(Err(message)) => eprintln!("{}", message),
+                None => {
+                    if let Err(e) = self.ask(&input) {
+                        eprintln!("ERROR: {}", e);
+                    }
+                }
+            }
+        }
+    }
+
+    fn ask(&mut self, input: &str) -> Result<(), GenericError> {
+        let usage = self.llm.context_prompt(input, self.look_back, self.summarize, &self.model, &mut self.session)?;
+        println!("\x1b[2m{}\x1b[0m", usage.indicator());
+        self.store.save(&mut self.session)?;
+        Ok(())
+    }
+
+    fn handle(&mut self, command: SlashCommand) -> Result<(), GenericError> {
+        match command {
+            SlashCommand::Model(None) => println!("{}", self.model.id),
+            SlashCommand::Model(Some(name)) => {
+                let model = self.registry.resolve(&name)?;
+                self.switch_model(model);
+                self.store.save(&mut self.session)?;
+                println!("Now using {}", self.model.id);
+            }
+            SlashCommand::System(None) => println!("{}", self.llm.system.as_deref().unwrap_or("(no system prompt)")),
+            SlashCommand::System(Some(system)) => {
+                self.llm.system = Some(system);
+                self.session.system = self.llm.system.clone();
+                self.store.save(&mut self.session)?;
+            }
+            SlashCommand::Clear => {
+                self.session = Session::new(&self.model.id, self.llm.system.clone());
+                println!("Started conversation {}", self.session.id);
+            }
+            SlashCommand::Save(id) => {
+                if let Some(id) = id {
+                    self.session.id = id;
+                }
+                self.store.save(&mut self.session)?;
+                println!("Saved as {}", self.session.id);
+            }
+            SlashCommand::Load(id) => {
+                let session = self.store.load(&id)?;
+                let model = self.registry.resolve(&session.model)?;
+                self.llm.system = session.system.clone();
+                self.session = session;
+                self.switch_model(model);
+                println!("Loaded {} ({} turns)", self.session.id, self.session.turns.len());
+            }
+            SlashCommand::Undo => {
+                match self.pop_turn() {
+                    Some(_) => self.store.save(&mut self.session)?,
+                    None => println!("Nothing to undo")
+                }
+            }
+            SlashCommand::Retry => {
+                let summarized = self.session.summarized;
+                match self.session.turns.last().cloned() {
+                    Some(turn) => {
+                        self.pop_turn();
+                        if let Err(e) = self.ask(&turn.user) {
+                            // Keep the old answer rather than losing the turn
+                            self.session.turns.push(turn);
+                            self.session.summarized = summarized;
+                            return Err(e);
+                        }
+                    }
+                    None => println!("Nothing to retry")
+                }
+            }
+            SlashCommand::Export(path) => {
+                std::fs::write(&path, self.session.to_markdown())?;
+                println!("Wrote {}", path);
+            }
+            SlashCommand::Tokens => {
+                let usage = self.llm.context_usage(&self.session, self.look_back, &self.model);
+                println!("{}", usage.indicator());
+            }
+            SlashCommand::Help => println!("{}", HELP.trim()),
+            SlashCommand::Quit => {}
+        }
+
+        Ok(())
+    }
+
+    fn switch_model(&mut self, model: ModelInfo) {
+        if model.provider != self.model.provider {
+            self.llm.backend = backend::from_provider(model.provider, &self.config.provider(model.provider.name()));
+        }
+        self.session.model = model.id.clone();
+        self.model = model;
+    }
+
+    fn pop_turn(&mut self) -> Option<()> {
+        self.session.turns.pop()?;
+        // The turn may already have been folded into the summary, which can't
+        // be taken apart again, so just stop counting it.
+        self.session.summarized = self.session.summarized.min(self.session.turns.len());
+        Some(())
+    }
+}
+
+/// Reads one prompt. A line with just `"""` starts a block that runs until
+/// the next `"""`, for pasting code or several paragraphs. `None` means the
+/// user pressed Ctrl-D or Ctrl-C and wants to leave.
+fn read_input(editor: &mut DefaultEditor) -> Result<Option<String>, GenericError> {
+    let line = match editor.readline(PROMPT) {
+        Ok(line) => line,
+        Err(ReadlineError::Eof) | Err(ReadlineError::Interrupted) => return Ok(None),
+        Err(e) => return Err(Box::new(e))
+    };
+
+    if line.trim() != MULTILINE {
+        return Ok(Some(line));
+    }
+
+    let mut lines: Vec<String> = Vec::new();
+
+    loop {
+        match editor.readline(CONTINUATION) {
+            Ok(line) if line.trim() == MULTILINE => break,
+            Ok(line) => lines.push(line),
+            Err(ReadlineError::Eof) => break,
+            // Ctrl-C throws the block away but stays in the session
+            Err(ReadlineError::Interrupted) => return Ok(Some(String::new())),
+            Err(e) => return Err(Box::new(e))
+        }
+    }
+
+    Ok(Some(lines.join("\n")))
+}
@@ -0,0 +1,134 @@
+use std::path::PathBuf;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::GenericError;
+
+/// A `context` conversation, saved as `<id>.json` after every turn so it can
+/// be picked up again with `context --resume <id>`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Session {
+    pub id: String,
+    pub model: String,
+    pub system: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub turns: Vec<Turn>,
+    /// Condensed version of `turns[..summarized]`, which no longer fit in the
+    /// model's context window.
+    #[serde(default)]
+    pub summary: Option<String>,
+    #[serde(default)]
+    pub summarized: usize
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Turn {
+    pub user: String,
+    pub assistant: String
+}
+
+impl Session {
+    pub fn new(model: &str, system: Option<String>) -> Self {
+        let now = Utc::now();
+        Session {
+            id: now.format("%Y%m%d-%H%M%S").to_string(),
+            model: model.to_string(),
+            system,
+            created_at: now,
+            updated_at: now,
+            turns: Vec::new(),
+            summary: None,
+            summarized: 0
+        }
+    }
+
+    /// First line of the first prompt, used to tell sessions apart in `--list`.
+    pub fn title(&self) -> String {
+        let first = self.turns.first()
+            .and_then(|turn| turn.user.lines().find(|line| !line.trim().is_empty()))
+            .unwrap_or("")
+            .trim();
+
+        if first.chars().count() > 50 {
+            format!("{}...", first.chars().take(47).collect::<String>())
+        } else {
+            first.to_string()
+        }
+    }
+
+    /// The conversation as a Markdown document, for `/export`.
+    pub fn to_markdown(&self) -> String {
+        let mut markdown = format!("# Conversation {}\n\n- Model: {}\n- Started: {}\n",
+            self.id, self.model, self.created_at.format("%Y-%m-%d %H:%M UTC"));
+
+        if let Some(system) = &self.system {
+            markdown.push_str(&format!("- System: {}\n", system));
+        }
+
+        for turn in &self.turns {
+            markdown.push_str(&format!("\n## User\n\n{}\n\n## Assistant\n\n{}\n", turn.user, turn.assistant));
+        }
+
+        markdown
+    }
+}
+
+pub struct SessionStore {
+    dir: PathBuf
+}
+
+impl SessionStore {
+    pub fn new(dir: PathBuf) -> Self {
+        SessionStore { dir }
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+
+    pub fn save(&self, session: &mut Session) -> Result<(), GenericError> {
+        std::fs::create_dir_all(&self.dir)?;
+        session.updated_at = Utc::now();
+
+        // Write to a temporary file first so a Ctrl-C mid write can't leave a
+        // half written session behind.
+        let path = self.path(&session.id);
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_string_pretty(session)?)?;
+        std::fs::rename(tmp, path)?;
+
+        Ok(())
+    }
+
+    pub fn load(&self, id: &str) -> Result<Session, GenericError> {
+        let path = self.path(id);
+        let text = std::fs::read_to_string(&path)
+            .map_err(|_| format!("no saved conversation with id {}, see `context --list`", id))?;
+
+        Ok(serde_json::from_str(&text)?)
+    }
+
+    /// All saved sessions, most recently updated first.
+    pub fn list(&self) -> Result<Vec<Session>, GenericError> {
+        let mut sessions = Vec::new();
+
+        if !self.dir.exists() {
+            return Ok(sessions);
+        }
+
+        for entry in std::fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().map(|ext| ext == "json").unwrap_or(false) {
+                match std::fs::read_to_string(&path).map(|text| serde_json::from_str::<Session>(&text)) {
+                    Ok(Ok(session)) => sessions.push(session),
+                    _ => eprintln!("WARNING: skipping unreadable session {}", path.display())
+                }
+            }
+        }
+
+        sessions.sort_by_key(|session| std::cmp::Reverse(session.updated_at));
+
+        Ok(sessions)
+    }
+}
@@ -0,0 +1,125 @@
+use std::io::BufRead;
+
+/// A single server-sent event. `event` is only set when the server names the
+/// event type (Anthropic does, OpenAI style APIs only send `data:` lines).
+#[derive(Debug, PartialEq)]
+pub struct SseEvent {
+    pub event: Option<String>,
+    pub data: String
+}
+
+/// Iterates over the events of a `text/event-stream` body as they arrive.
+pub struct SseEvents<R: BufRead> {
+    reader: R
+}
+
+impl<R: BufRead> SseEvents<R> {
+    pub fn new(reader: R) -> Self {
+        SseEvents { reader }
+    }
+}
+
+impl<R: BufRead> Iterator for SseEvents<R> {
+    type Item = Result<SseEvent, std::io::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut event = None;
+        let mut data: Vec<String> = Vec::new();
+
+        loop {
+            let mut line = String::new();
+            match self.reader.read_line(&mut line) {
+                Ok(0) => {
+                    // End of stream, flush whatever was buffered
+                    if data.is_empty() {
+                        return None;
+                    }
+                    break;
+                }
+                Ok(_) => {}
+                Err(e) => return Some(Err(e))
+            }
+
+            let line = line.trim_end_matches(['\r', '\n']);
+
+            if line.is_empty() {
+                if data.is_empty() && event.is_none() {
+                    continue;
+                }
+                break;
+            }
+
+            // Comment lines are used as keep-alives
+            if line.starts_with(':') {
+                continue;
+            }
+
+            let (field, value) = match line.split_once(':') {
+                Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+                None => (line, "")
+            };
+
+            match field {
+                "event" => event = Some(value.to_string()),
+                "data" => data.push(value.to_string()),
+                _ => {}
+            }
+        }
+
+        Some(Ok(SseEvent { event, data: data.join("\n") }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn events(body: &str) -> Vec<SseEvent> {
+        SseEvents::new(body.as_bytes()).collect::<Result<_, _>>().unwrap()
+    }
+
+    fn data(text: &str) -> SseEvent {
+        SseEvent { event: None, data: text.to_string() }
+    }
+
+    #[test]
+    fn joins_multi_line_data() {
+        let body = "event: message\ndata: first\ndata: second\n\ndata: third\n\n";
+
+        assert_eq!(events(body), vec![
+            SseEvent { event: Some("message".to_string()), data: "first\nsecond".to_string() },
+            data("third")
+        ]);
+    }
+
+    #[test]
+    fn skips_comment_keep_alives() {
+        let body = ": ping\n\ndata: one\n: still here\ndata: two\n\n:\n\n";
+
+        assert_eq!(events(body), vec![data("one\ntwo")]);
+    }
+
+    #[test]
+    fn reads_crlf_line_endings() {
+        let body = "event: delta\r\ndata: {\"a\":1}\r\n\r\ndata: [DONE]\r\n\r\n";
+
+        assert_eq!(events(body), vec![
+            SseEvent { event: Some("delta".to_string()), data: "{\"a\":1}".to_string() },
+            data("[DONE]")
+        ]);
+    }
+
+    #[test]
+    fn strips_only_one_leading_space() {
+        let body = "data:tight\n\ndata: spaced\n\ndata:  indented\n\n";
+
+        assert_eq!(events(body), vec![data("tight"), data("spaced"), data(" indented")]);
+    }
+
+    #[test]
+    fn flushes_final_event_without_blank_line() {
+        assert_eq!(events("data: one\n\ndata: last"), vec![data("one"), data("last")]);
+        assert_eq!(events("data: last\n"), vec![data("last")]);
+        assert!(events("").is_empty());
+    }
+}
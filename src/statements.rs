@@ -0,0 +1,434 @@
+use std::path::{Path, PathBuf};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum StatementKind {
+    Income,
+    BalanceSheet,
+    CashFlow
+}
+
+impl StatementKind {
+    pub const ALL: [StatementKind; 3] = [StatementKind::Income, StatementKind::BalanceSheet, StatementKind::CashFlow];
+
+    /// Name of the statement's files in a ticker folder, without extension.
+    pub fn file_stem(&self) -> &'static str {
+        match self {
+            StatementKind::Income => "income_statement",
+            StatementKind::BalanceSheet => "balance_sheet_statement",
+            StatementKind::CashFlow => "cash_flow_statement"
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            StatementKind::Income => "income statement",
+            StatementKind::BalanceSheet => "balance sheet",
+            StatementKind::CashFlow => "cash flow statement"
+        }
+    }
+
+    /// The statement a file is about going by its name, e.g.
+    /// `AAPL_balance-sheet.csv`.
+    pub fn guess(file_name: &str) -> Option<Self> {
+        let name = normalize(file_name);
+        if name.contains("income") || name.contains("operations") || name.contains("profit") {
+            Some(StatementKind::Income)
+        } else if name.contains("balance") || name.contains("financial position") {
+            Some(StatementKind::BalanceSheet)
+        } else if name.contains("cash") {
+            Some(StatementKind::CashFlow)
+        } else {
+            None
+        }
+    }
+}
+
+/// The file holding the `kind` statement in a ticker folder: the imported
+/// `.json` when there is one, the pasted `.txt` otherwise.
+pub fn statement_file(dir: &Path, kind: StatementKind) -> PathBuf {
+    let imported = dir.join(format!("{}.json", kind.file_stem()));
+    if imported.exists() {
+        imported
+    } else {
+        dir.join(format!("{}.txt", kind.file_stem()))
+    }
+}
+
+/// A statement as a table of figures, one column per period.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FinancialStatement {
+    pub currency: Option<String>,
+    /// What the figures are multiplied by to get units, e.g. 1e6 for a
+    /// statement given in millions.
+    #[serde(default = "unit_scale")]
+    pub scale: f64,
+    pub periods: Vec<String>,
+    pub line_items: Vec<LineItem>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LineItem {
+    pub name: String,
+    pub values: Vec<Option<f64>>
+}
+
+fn unit_scale() -> f64 {
+    1.0
+}
+
+impl FinancialStatement {
+    /// Reads an imported `.json` statement, or parses a pasted one.
+    pub fn read(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+        if path.extension().is_some_and(|extension| extension == "json") {
+            from_json(&text)
+        } else {
+            parse(&text)
+        }
+    }
+
+    /// Fails unless there are periods and every line item has one value for
+    /// each.
+    pub fn check(&self) -> Result<(), String> {
+        if self.periods.is_empty() {
+            return Err("the statement has no periods".to_string());
+        }
+        if let Some(item) = self.line_items.iter().find(|item| item.values.len() != self.periods.len()) {
+            return Err(format!("\"{}\" has {} values for {} periods", item.name, item.values.len(), self.periods.len()));
+        }
+        Ok(())
+    }
+
+    /// The statement as a tab separated table that `parse` reads back, for
+    /// prompts.
+    pub fn to_text(&self) -> String {
+        let scale = match self.scale {
+            1e9 => Some("billions"),
+            1e6 => Some("millions"),
+            1e3 => Some("thousands"),
+            _ => None
+        };
+        let mut lines = Vec::new();
+        match (scale, &self.currency) {
+            (Some(scale), Some(currency)) => lines.push(format!("In {} of {}", scale, currency)),
+            (Some(scale), None) => lines.push(format!("In {}", scale)),
+            (None, Some(currency)) => lines.push(format!("In {}", currency)),
+            (None, None) => {}
+        }
+
+        lines.push(format!("Breakdown\t{}", self.periods.join("\t")));
+        for item in &self.line_items {
+            let values: Vec<String> = item.values.iter()
+                .map(|value| value.map_or("-".to_string(), format_value))
+                .collect();
+            lines.push(format!("{}\t{}", item.name, values.join("\t")));
+        }
+
+        lines.join("\n")
+    }
+
+    /// The first line item called one of `names`, tried in order, with the
+    /// label's case and punctuation ignored.
+    pub fn find(&self, names: &[&str]) -> Option<&LineItem> {
+        names.iter().find_map(|name| {
+            let name = normalize(name);
+            self.line_items.iter().find(|item| normalize(&item.name) == name)
+        })
+    }
+
+    /// Value in units of the first of `names` in `column`.
+    pub fn value(&self, names: &[&str], column: usize) -> Option<f64> {
+        self.find(names)?.values.get(column).copied().flatten().map(|value| value * self.scale)
+    }
+
+    /// The column of `period`, which may be labelled differently in another
+    /// statement: `FY2023` matches `12/31/2023` as long as 2023 appears once.
+    pub fn column(&self, period: &str) -> Option<usize> {
+        if let Some(column) = self.periods.iter().position(|p| p == period) {
+            return Some(column);
+        }
+
+        let year = year(period)?;
+        let mut matches = self.periods.iter().enumerate().filter(|(_, p)| year_of(p) == Some(year));
+        match (matches.next(), matches.next()) {
+            (Some((column, _)), None) => Some(column),
+            _ => None
+        }
+    }
+
+    /// The column of the most recent period, going by the years in the
+    /// labels, or the first column when they have none.
+    pub fn newest(&self) -> Option<usize> {
+        let years: Vec<Option<u32>> = self.periods.iter().map(|period| year_of(period)).collect();
+        if years.iter().all(Option::is_none) {
+            return if self.periods.is_empty() { None } else { Some(0) };
+        }
+        // The first of equal years, as statements list the newest first
+        years.iter().enumerate().rev().max_by_key(|(_, year)| **year).map(|(column, _)| column)
+    }
+
+    /// The column before `column` in time. Statements usually list the
+    /// newest period first, unless their years say otherwise.
+    pub fn previous(&self, column: usize) -> Option<usize> {
+        let first = self.periods.first().and_then(|p| year_of(p));
+        let last = self.periods.last().and_then(|p| year_of(p));
+
+        match (first, last) {
+            (Some(first), Some(last)) if first < last => column.checked_sub(1),
+            _ => Some(column + 1).filter(|previous| *previous < self.periods.len())
+        }
+    }
+}
+
+/// Reads a statement pasted as text: a header line of periods (`FY2023`,
+/// `12/31/2023`, `TTM`, ...) followed by one line per item, with the
+/// columns separated by tabs or runs of spaces. Figures may have thousands
+/// separators, currency signs or parentheses for negatives, and a note like
+/// "in millions" sets the scale.
+pub fn parse(text: &str) -> Result<FinancialStatement, String> {
+    from_rows(text.lines().map(cells))
+}
+
+/// Reads a statement in its own JSON form, as written by `import`.
+pub fn from_json(text: &str) -> Result<FinancialStatement, String> {
+    let statement: FinancialStatement = serde_json::from_str(text).map_err(|e| e.to_string())?;
+    statement.check()?;
+    Ok(statement)
+}
+
+/// A statement from rows of cells, the first cell of each the label: a
+/// header row of periods, then one row per line item. Rows of text before
+/// the first item may give the scale and currency.
+pub fn from_rows(rows: impl IntoIterator<Item = Vec<String>>) -> Result<FinancialStatement, String> {
+    let mut statement = FinancialStatement { currency: None, scale: 1.0, periods: Vec::new(), line_items: Vec::new() };
+
+    for cells in rows {
+        let line = cells.join(" ");
+        let Some((label, rest)) = cells.split_first() else { continue };
+
+        // A later header replaces one found in a title like "Income Statement 2023"
+        if statement.line_items.is_empty() {
+            let periods: Vec<&String> = if is_period(label) { cells.iter().collect() } else { rest.iter().collect() };
+            if !periods.is_empty() && periods.iter().all(|cell| is_period(cell)) {
+                statement.periods = periods.into_iter().cloned().collect();
+                continue;
+            }
+        }
+
+        let values: Option<Vec<Option<f64>>> = rest.iter().map(|cell| parse_value(cell)).collect();
+
+        match values {
+            Some(mut values) if !values.is_empty() && !statement.periods.is_empty() => {
+                values.resize(statement.periods.len(), None);
+                statement.line_items.push(LineItem { name: label.trim_end_matches(':').to_string(), values });
+            }
+            _ => {
+                let lower = line.to_lowercase();
+                if statement.line_items.is_empty() {
+                    if let Some(scale) = scale(&lower) {
+                        statement.scale = scale;
+                    }
+                }
+                if statement.currency.is_none() {
+                    statement.currency = ["USD", "EUR", "GBP", "JPY", "CHF", "CAD", "CNY"].iter()
+                        .find(|code| line.split(|c: char| !c.is_ascii_alphabetic()).any(|word| word == **code))
+                        .map(|code| code.to_string());
+                }
+            }
+        }
+    }
+
+    if statement.periods.is_empty() {
+        return Err("no header line with the periods (e.g. FY2023 or 12/31/2023) found".to_string());
+    }
+    if statement.line_items.is_empty() {
+        return Err("no line items with figures found".to_string());
+    }
+
+    Ok(statement)
+}
+
+fn cells(line: &str) -> Vec<String> {
+    let mut cells: Vec<String> = if line.contains('\t') {
+        line.split('\t').map(str::trim).filter(|cell| !cell.is_empty()).map(String::from).collect()
+    } else {
+        line.split("  ").map(str::trim).filter(|cell| !cell.is_empty()).map(String::from).collect()
+    };
+
+    // Single spaces only: peel the figures off the end of the label
+    if cells.len() == 1 {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let mut label_end = words.len();
+        while label_end > 1 && (parse_value(words[label_end - 1]).is_some() || is_period(words[label_end - 1])) {
+            label_end -= 1;
+        }
+        if label_end < words.len() {
+            cells = std::iter::once(words[..label_end].join(" "))
+                .chain(words[label_end..].iter().map(|word| word.to_string()))
+                .collect();
+        }
+    }
+
+    cells
+}
+
+/// `Some(None)` for a cell marking a missing figure, `None` for one that
+/// isn't a figure at all.
+fn parse_value(cell: &str) -> Option<Option<f64>> {
+    let cell = cell.trim();
+
+    if matches!(cell, "-" | "--" | "—" | "–" | "n/a" | "N/A" | "NA" | "NM" | "nm" | "*") {
+        return Some(None);
+    }
+    if cell.ends_with('%') {
+        return None;
+    }
+
+    let (negative, digits) = match cell.strip_prefix('(').and_then(|rest| rest.strip_suffix(')')) {
+        Some(inner) => (true, inner),
+        None => match cell.strip_prefix('-').or_else(|| cell.strip_prefix('−')) {
+            Some(rest) => (true, rest),
+            None => (false, cell)
+        }
+    };
+
+    let digits: String = digits.chars().filter(|c| !matches!(c, ',' | '$' | '€' | '£' | ' ')).collect();
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit() || c == '.') {
+        return None;
+    }
+
+    let value: f64 = digits.parse().ok()?;
+    Some(Some(if negative { -value } else { value }))
+}
+
+fn is_period(cell: &str) -> bool {
+    let lower = cell.trim().to_lowercase();
+    matches!(lower.as_str(), "ttm" | "ltm") || (year(cell).is_some() && !cell.contains(','))
+}
+
+fn scale(line: &str) -> Option<f64> {
+    if line.contains("billion") {
+        Some(1e9)
+    } else if line.contains("million") {
+        Some(1e6)
+    } else if line.contains("thousand") {
+        Some(1e3)
+    } else {
+        None
+    }
+}
+
+/// A year between 1900 and 2100 standing on its own in `period`.
+pub fn year(period: &str) -> Option<u32> {
+    period.split(|c: char| !c.is_ascii_digit())
+        .filter(|digits| digits.len() == 4)
+        .filter_map(|digits| digits.parse().ok())
+        .find(|year| (1900..=2100).contains(year))
+}
+
+/// Like `year`, with trailing twelve months counting as the newest.
+fn year_of(period: &str) -> Option<u32> {
+    if matches!(period.trim().to_lowercase().as_str(), "ttm" | "ltm") {
+        return Some(u32::MAX);
+    }
+    year(period)
+}
+
+/// `value` with thousands separators, as statements show figures.
+fn format_value(value: f64) -> String {
+    let text = format!("{}", value.abs());
+    let (whole, fraction) = text.split_once('.').map_or((text.as_str(), None), |(whole, fraction)| (whole, Some(fraction)));
+
+    let mut grouped = String::new();
+    for (i, digit) in whole.chars().enumerate() {
+        if i > 0 && (whole.len() - i) % 3 == 0 {
+            grouped.push(',');
+        }
+        grouped.push(digit);
+    }
+    if let Some(fraction) = fraction {
+        grouped.push('.');
+        grouped.push_str(fraction);
+    }
+
+    if value < 0.0 { format!("-{}", grouped) } else { grouped }
+}
+
+fn normalize(name: &str) -> String {
+    name.to_lowercase()
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { ' ' })
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_a_pasted_statement() {
+        let text = "\
+Income Statement (in millions of USD)
+Breakdown\tTTM\t9/30/2023\t9/30/2022
+Total Revenue\t385,706\t383,285\t394,328
+Cost of Revenue\t(210,352)\t(214,137)\t(223,546)
+Other Income\t-\t$1,200.5\t--
+Gross Margin %\t45.5%\t44.1%\t43.3%
+";
+        let statement = parse(text).unwrap();
+
+        assert_eq!(statement.periods, ["TTM", "9/30/2023", "9/30/2022"]);
+        assert_eq!(statement.scale, 1e6);
+        assert_eq!(statement.currency.as_deref(), Some("USD"));
+        assert_eq!(statement.line_items.len(), 3);
+        assert_eq!(statement.line_items[1].values, [Some(-210352.0), Some(-214137.0), Some(-223546.0)]);
+        assert_eq!(statement.line_items[2].values, [None, Some(1200.5), None]);
+        assert_eq!(statement.value(&["revenue", "total revenue"], 1), Some(383_285e6));
+    }
+
+    #[test]
+    fn parses_space_separated_columns() {
+        let text = "\
+                      FY2021    FY2022    FY2023
+Net income            94,680    99,803    96,995
+Total assets 351,002 352,755 352,583
+";
+        let statement = parse(text).unwrap();
+
+        assert_eq!(statement.periods, ["FY2021", "FY2022", "FY2023"]);
+        assert_eq!(statement.find(&["Total Assets"]).unwrap().values, [Some(351002.0), Some(352755.0), Some(352583.0)]);
+        assert_eq!(statement.previous(2), Some(1));
+        assert_eq!(statement.previous(0), None);
+        assert_eq!(statement.column("12/31/2022"), Some(1));
+    }
+
+    #[test]
+    fn text_round_trips() {
+        let statement = FinancialStatement {
+            currency: Some("USD".to_string()),
+            scale: 1e6,
+            periods: vec!["2023-09-30".to_string(), "2022-09-24".to_string()],
+            line_items: vec![
+                LineItem { name: "Total Revenue".to_string(), values: vec![Some(383285.0), Some(394328.5)] },
+                LineItem { name: "Capital Expenditure".to_string(), values: vec![Some(-10959.0), None] }
+            ]
+        };
+
+        let text = statement.to_text();
+
+        assert!(text.starts_with("In millions of USD\nBreakdown\t2023-09-30\t2022-09-24\nTotal Revenue\t383,285\t394,328.5\n"));
+        assert_eq!(parse(&text).unwrap(), statement);
+        assert_eq!(from_json(&serde_json::to_string(&statement).unwrap()).unwrap(), statement);
+        assert!(from_json(r#"{"periods": ["FY2023"], "line_items": [{"name": "Revenue", "values": [1, 2]}]}"#).is_err());
+    }
+
+    #[test]
+    fn text_without_periods_is_an_error() {
+        assert!(parse("Revenue went up a lot this year.").is_err());
+    }
+}
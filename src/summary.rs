@@ -0,0 +1,361 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::error::LlmError;
+use crate::tokens;
+
+/// A summary of pages `first_page..=last_page`, counted from 1.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Summary {
+    pub first_page: usize,
+    pub last_page: usize,
+    pub text: String
+}
+
+impl Summary {
+    pub fn pages(&self) -> String {
+        if self.first_page == self.last_page {
+            format!("p. {}", self.first_page)
+        } else {
+            format!("pp. {}-{}", self.first_page, self.last_page)
+        }
+    }
+}
+
+/// A chunk summarized by an earlier run. A page too big for one chunk is
+/// split into several with the same page numbers, so a chunk is known by its
+/// place in the document and a hash of its text.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Checkpoint {
+    pub index: usize,
+    /// SHA-256 of the chunk's text.
+    pub hash: String,
+    pub summary: Summary
+}
+
+/// A document summarized at three levels: a note per chunk of pages, a
+/// summary per section of chunks and one overview, all citing pages.
+pub struct DocumentSummary {
+    pub overview: String,
+    pub sections: Vec<Summary>,
+    pub chunks: Vec<Summary>
+}
+
+impl DocumentSummary {
+    pub fn to_markdown(&self, title: &str) -> String {
+        let mut markdown = format!("# {}\n\n## Overview\n\n{}\n", title, self.overview.trim());
+
+        // With a single section it would only repeat the overview
+        if self.sections.len() > 1 {
+            markdown.push_str("\n## Sections\n");
+            for section in &self.sections {
+                markdown.push_str(&format!("\n### {}\n\n{}\n", section.pages(), section.text.trim()));
+            }
+        }
+
+        markdown.push_str("\n## Page notes\n");
+        for chunk in &self.chunks {
+            markdown.push_str(&format!("\n### {}\n\n{}\n", chunk.pages(), chunk.text.trim()));
+        }
+
+        markdown
+    }
+}
+
+/// Summarizes long documents map-reduce style: pages are packed into chunks
+/// of at most `budget` tokens and summarized one by one, then the summaries
+/// are packed and summarized again until one overview remains. Every level
+/// is asked to keep citing the pages its facts come from.
+pub struct Summarizer<'a> {
+    pub budget: usize,
+    /// Sends a prompt to the model and returns the reply.
+    pub ask: &'a dyn Fn(&str) -> Result<String, LlmError>
+}
+
+impl Summarizer<'_> {
+    /// `pages` are the text and index (from 0) of each page, as returned by
+    /// `pdf_pages`. Chunks with a checkpoint in `done` aren't asked about
+    /// again, and every new chunk summary is handed to `on_chunk`.
+    pub fn summarize(&self, title: &str, pages: &[(i32, String)], done: &[Checkpoint], on_chunk: &mut dyn FnMut(&Checkpoint)) -> Result<DocumentSummary, LlmError> {
+        let pages: Vec<Summary> = pages.iter()
+            .filter(|(_, text)| !text.trim().is_empty())
+            .map(|(i, text)| Summary { first_page: *i as usize + 1, last_page: *i as usize + 1, text: text.clone() })
+            .collect();
+
+        let packed = pack(&pages, self.budget);
+        let mut chunks = Vec::new();
+        for (n, chunk) in packed.iter().enumerate() {
+            let hash = format!("{:x}", Sha256::digest(chunk.text.as_bytes()));
+            if let Some(earlier) = done.iter().find(|d| d.index == n && d.hash == hash) {
+                chunks.push(earlier.summary.clone());
+                continue;
+            }
+
+            println!("  {} {} ({}/{})", title, chunk.pages(), n + 1, packed.len());
+            let prompt = format!(r#"
+            - You are given pages of the document "{}", each starting with a [page N] marker.
+            - Summarize what these pages say for someone who is not financially savvy: results, figures, risks, plans and anything unusual.
+            - Cite the page every statement comes from as (p. N).
+            - It is imperative for you to respect and avoid tampering with financial figures. It is imperitive to not interchange millions and billions, and substitute a comma with a period and so on.
+            - Write plain paragraphs, no preamble.
+            {}
+            "#, title, chunk.text);
+            let summary = Summary { text: (self.ask)(&prompt)?, ..chunk.clone() };
+            on_chunk(&Checkpoint { index: n, hash, summary: summary.clone() });
+            chunks.push(summary);
+        }
+
+        if chunks.is_empty() {
+            return Ok(DocumentSummary { overview: String::new(), sections: Vec::new(), chunks });
+        }
+
+        let sections = self.reduce(title, &chunks, "section")?;
+
+        let mut level = sections.clone();
+        while level.len() > 1 && total_tokens(&level) > self.budget {
+            level = self.reduce(title, &level, "section")?;
+        }
+
+        let overview = match level.as_slice() {
+            [only] if sections.len() == 1 => only.text.clone(),
+            _ => (self.ask)(&self.reduce_prompt(title, &level, "document"))?
+        };
+
+        Ok(DocumentSummary { overview, sections, chunks })
+    }
+
+    /// Summarizes `summaries` a budget's worth at a time.
+    fn reduce(&self, title: &str, summaries: &[Summary], level: &str) -> Result<Vec<Summary>, LlmError> {
+        if summaries.len() == 1 {
+            return Ok(summaries.to_vec());
+        }
+
+        let mut reduced = Vec::new();
+        for group in group(summaries, self.budget) {
+            println!("  {} {} {}", title, level, span(&group).pages());
+            let text = (self.ask)(&self.reduce_prompt(title, &group, level))?;
+            reduced.push(Summary { text, ..span(&group) });
+        }
+        Ok(reduced)
+    }
+
+    fn reduce_prompt(&self, title: &str, summaries: &[Summary], level: &str) -> String {
+        let notes: Vec<String> = summaries.iter()
+            .map(|summary| format!("[{}]\n{}", summary.pages(), summary.text.trim()))
+            .collect();
+
+        format!(r#"
+        - You are given summaries of consecutive parts of the document "{}", each starting with the pages it covers.
+        - Combine them into one {} summary: the main story first, then the key figures, risks and outlook.
+        - Keep the page citations, in the form (p. N) or (pp. N-M), for every statement.
+        - It is imperative for you to respect and avoid tampering with financial figures.
+        - Write plain paragraphs, no preamble.
+        {}
+        "#, title, level, notes.join("\n\n"))
+    }
+}
+
+/// Packs consecutive pages into chunks of at most `budget` tokens, each page
+/// marked with its number. A page too big for one chunk is split by lines.
+fn pack(pages: &[Summary], budget: usize) -> Vec<Summary> {
+    let mut chunks: Vec<Summary> = Vec::new();
+    let mut used = 0;
+
+    for page in pages {
+        for piece in split(&page.text, budget) {
+            let text = format!("[page {}]\n{}\n", page.first_page, piece.trim());
+            let size = tokens::count(&text);
+
+            match chunks.last_mut() {
+                Some(chunk) if used + size <= budget => {
+                    chunk.text.push_str(&text);
+                    chunk.last_page = page.first_page;
+                    used += size;
+                }
+                _ => {
+                    chunks.push(Summary { text, ..page.clone() });
+                    used = size;
+                }
+            }
+        }
+    }
+
+    chunks
+}
+
+/// `text` in pieces of at most `budget` tokens, cut between lines.
+fn split(text: &str, budget: usize) -> Vec<String> {
+    if tokens::count(text) <= budget {
+        return vec![text.to_string()];
+    }
+
+    let mut pieces = vec![String::new()];
+    let mut used = 0;
+
+    for line in text.lines() {
+        let size = tokens::count(line) + 1;
+        if used + size > budget && used > 0 {
+            pieces.push(String::new());
+            used = 0;
+        }
+        let piece = pieces.last_mut().unwrap();
+        piece.push_str(line);
+        piece.push('\n');
+        used += size;
+    }
+
+    pieces
+}
+
+/// Consecutive runs of `summaries` adding up to at most `budget` tokens, at
+/// least two per run so every round gets shorter.
+fn group(summaries: &[Summary], budget: usize) -> Vec<Vec<Summary>> {
+    let mut groups: Vec<Vec<Summary>> = Vec::new();
+    let mut used = 0;
+
+    for summary in summaries {
+        let size = tokens::count(&summary.text);
+        match groups.last_mut() {
+            Some(group) if used + size <= budget || group.len() < 2 => {
+                group.push(summary.clone());
+                used += size;
+            }
+            _ => {
+                groups.push(vec![summary.clone()]);
+                used = size;
+            }
+        }
+    }
+
+    // A lone summary at the end would come back unchanged
+    if groups.len() > 1 && groups.last().is_some_and(|group| group.len() == 1) {
+        let last = groups.pop().unwrap();
+        groups.last_mut().unwrap().extend(last);
+    }
+
+    groups
+}
+
+fn span(summaries: &[Summary]) -> Summary {
+    Summary {
+        first_page: summaries.first().map_or(0, |s| s.first_page),
+        last_page: summaries.last().map_or(0, |s| s.last_page),
+        text: String::new()
+    }
+}
+
+fn total_tokens(summaries: &[Summary]) -> usize {
+    summaries.iter().map(|summary| tokens::count(&summary.text)).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    fn pages(count: i32, words: usize) -> Vec<(i32, String)> {
+        (0..count).map(|i| (i, format!("revenue grew {} ", i).repeat(words))).collect()
+    }
+
+    #[test]
+    fn packs_pages_up_to_the_budget() {
+        let pages: Vec<Summary> = pages(5, 10).into_iter()
+            .map(|(i, text)| Summary { first_page: i as usize + 1, last_page: i as usize + 1, text })
+            .collect();
+
+        let chunks = pack(&pages, 150);
+
+        assert!(chunks.len() > 1 && chunks.len() < 5);
+        assert_eq!(chunks.first().unwrap().first_page, 1);
+        assert_eq!(chunks.last().unwrap().last_page, 5);
+        for pair in chunks.windows(2) {
+            assert_eq!(pair[0].last_page + 1, pair[1].first_page);
+        }
+        assert!(chunks[0].text.starts_with("[page 1]\n"));
+        assert!(chunks.iter().all(|chunk| tokens::count(&chunk.text) <= 150));
+    }
+
+    #[test]
+    fn splits_a_page_bigger_than_the_budget() {
+        let page = Summary { first_page: 7, last_page: 7, text: "net income rose sharply\n".repeat(100) };
+
+        let chunks = pack(&[page], 100);
+
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|chunk| chunk.first_page == 7 && chunk.text.starts_with("[page 7]")));
+    }
+
+    #[test]
+    fn reduces_to_one_overview_keeping_page_ranges() {
+        let prompts = RefCell::new(Vec::new());
+        let ask = |prompt: &str| -> Result<String, LlmError> {
+            prompts.borrow_mut().push(prompt.to_string());
+            Ok(format!("note {} (p. 1) {}", prompts.borrow().len(), "margin ".repeat(20)))
+        };
+
+        let summarizer = Summarizer { budget: 60, ask: &ask };
+        let summary = summarizer.summarize("10-K", &pages(6, 15), &[], &mut |_| {}).unwrap();
+
+        assert!(summary.chunks.len() > 1);
+        assert!(summary.sections.len() < summary.chunks.len());
+        assert_eq!(summary.sections.first().unwrap().first_page, 1);
+        assert_eq!(summary.sections.last().unwrap().last_page, 6);
+        assert!(prompts.borrow().last().unwrap().contains("document summary"));
+        assert!(summary.overview.starts_with(&format!("note {} ", prompts.borrow().len())));
+
+        let markdown = summary.to_markdown("10-K");
+        assert!(markdown.starts_with("# 10-K\n\n## Overview\n\n"));
+        assert!(markdown.contains("### pp. 1-"));
+    }
+
+    #[test]
+    fn skips_chunks_done_before() {
+        let asked = RefCell::new(0);
+        let ask = |_: &str| -> Result<String, LlmError> {
+            *asked.borrow_mut() += 1;
+            Ok("new".to_string())
+        };
+
+        let summarizer = Summarizer { budget: 60, ask: &ask };
+        let mut first = Vec::new();
+        summarizer.summarize("10-K", &pages(3, 15), &[], &mut |chunk| first.push(chunk.clone())).unwrap();
+        assert_eq!(first.len(), 3);
+
+        *asked.borrow_mut() = 0;
+        let mut checkpointed = Vec::new();
+        let summary = summarizer.summarize("10-K", &pages(3, 15), &first[..2], &mut |chunk| checkpointed.push(chunk.clone())).unwrap();
+
+        assert_eq!(checkpointed.len(), 1);
+        assert_eq!(checkpointed[0].summary.first_page, 3);
+        assert_eq!(summary.chunks.len(), 3);
+        // The third chunk, then combining the three
+        assert_eq!(*asked.borrow(), 2);
+    }
+
+    #[test]
+    fn resumes_inside_a_split_page() {
+        let asked = RefCell::new(Vec::new());
+        let ask = |prompt: &str| -> Result<String, LlmError> {
+            asked.borrow_mut().push(prompt.to_string());
+            Ok(format!("summary {}", asked.borrow().len()))
+        };
+        let page = vec![(0, (0..40).map(|i| format!("line {} of the notes\n", i)).collect::<String>())];
+
+        let summarizer = Summarizer { budget: 60, ask: &ask };
+        let mut first = Vec::new();
+        summarizer.summarize("10-K", &page, &[], &mut |chunk| first.push(chunk.clone())).unwrap();
+        assert!(first.len() > 2);
+        assert!(first.iter().all(|chunk| chunk.summary.pages() == "p. 1"));
+
+        // Interrupted after the first piece of the page
+        let before = asked.borrow().len();
+        let summary = summarizer.summarize("10-K", &page, &first[..1], &mut |_| {}).unwrap();
+
+        assert_eq!(summary.chunks.len(), first.len());
+        assert_eq!(summary.chunks[0].text, first[0].summary.text);
+        // The other pieces are summarized again, not given the first one's summary
+        assert!(summary.chunks[1..].iter().all(|chunk| chunk.text != first[0].summary.text));
+        let resumed = asked.borrow()[before].clone();
+        assert!(resumed.contains("[page 1]") && !resumed.contains("line 0 of"));
+    }
+}
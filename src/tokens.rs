@@ -0,0 +1,41 @@
+use tiktoken_rs::cl100k_base_singleton;
+
+// Chat formats wrap every message in a few tokens of role markers.
+const MESSAGE_OVERHEAD: usize = 4;
+const REPLY_PRIMER: usize = 3;
+
+/// Counts tokens with the cl100k tokenizer. Providers each use their own
+/// vocabulary, but cl100k lands within a few percent for English text, which
+/// is close enough to budget a context window.
+pub fn count(text: &str) -> usize {
+    cl100k_base_singleton().lock().encode_ordinary(text).len()
+}
+
+pub fn count_message(content: &str) -> usize {
+    count(content) + MESSAGE_OVERHEAD
+}
+
+/// Tokens a request with these message contents takes up in the window.
+pub fn count_messages<'a>(contents: impl IntoIterator<Item = &'a str>) -> usize {
+    contents.into_iter().map(count_message).sum::<usize>() + REPLY_PRIMER
+}
+
+/// How much of the context window the last request used.
+pub struct ContextUsage {
+    pub used: usize,
+    pub window: usize,
+    /// Turns left out because they no longer fit.
+    pub dropped: usize
+}
+
+impl ContextUsage {
+    pub fn indicator(&self) -> String {
+        let percent = self.used * 100 / self.window.max(1);
+        let mut indicator = format!("[context {}/{} tokens, {}%", self.used, self.window, percent);
+        if self.dropped > 0 {
+            indicator.push_str(&format!(", {} older turns left out", self.dropped));
+        }
+        indicator.push(']');
+        indicator
+    }
+}
@@ -0,0 +1,141 @@
+use std::path::{Component, Path, PathBuf};
+use serde_json::{json, Value};
+
+use crate::backend::ToolSpec;
+use crate::chat::ToolCall;
+use crate::helper::{pdf_pages, read_attachment};
+use crate::GenericError;
+
+// Longest tool result handed back, so one big filing can't fill the window.
+const MAX_RESULT_CHARS: usize = 20_000;
+
+type Handler = Box<dyn Fn(&Value) -> Result<String, GenericError>>;
+
+/// Rust functions the model can call, see `LLM::prompt_with_tools`.
+#[derive(Default)]
+pub struct Tools {
+    tools: Vec<(ToolSpec, Handler)>
+}
+
+impl Tools {
+    /// Adds a tool. `parameters` is the JSON Schema of the arguments object
+    /// `handler` gets called with.
+    pub fn register(&mut self, name: &str, description: &str, parameters: Value, handler: impl Fn(&Value) -> Result<String, GenericError> + 'static) -> &mut Self {
+        let spec = ToolSpec { name: name.to_string(), description: description.to_string(), parameters };
+        self.tools.push((spec, Box::new(handler)));
+        self
+    }
+
+    pub fn specs(&self) -> Vec<ToolSpec> {
+        self.tools.iter().map(|(spec, _)| spec.clone()).collect()
+    }
+
+    /// Runs `call`. Failures go back to the model as text rather than ending
+    /// the conversation, so it gets a chance to fix its arguments.
+    pub fn call(&self, call: &ToolCall) -> String {
+        let result = match self.tools.iter().find(|(spec, _)| spec.name == call.name) {
+            Some((_, handler)) => handler(&call.arguments).unwrap_or_else(|e| format!("error: {}", e)),
+            None => format!("error: there is no tool called {}", call.name)
+        };
+
+        if result.chars().count() > MAX_RESULT_CHARS {
+            let cut: String = result.chars().take(MAX_RESULT_CHARS).collect();
+            format!("{}\n[cut off after {} characters]", cut, MAX_RESULT_CHARS)
+        } else {
+            result
+        }
+    }
+}
+
+/// Tools for browsing the statements, reports and analyses kept in the ticker
+/// folders under `data_root`.
+pub fn filings(data_root: &str) -> Tools {
+    let mut tools = Tools::default();
+
+    let root = PathBuf::from(data_root);
+    tools.register(
+        "list_filings",
+        "List the tickers with filings, or the files kept for one ticker.",
+        json!({
+            "type": "object",
+            "properties": {
+                "ticker": { "type": "string", "description": "Ticker symbol, leave out to list the tickers" }
+            }
+        }),
+        move |arguments| {
+            match arguments["ticker"].as_str() {
+                Some(ticker) => {
+                    let dir = within(&root, ticker)?;
+                    list_files(&dir, &dir, 1)
+                }
+                None => list_files(&root, &root, 0)
+            }
+        }
+    );
+
+    let root = PathBuf::from(data_root);
+    tools.register(
+        "read_filing",
+        "Read a file listed by list_filings. PDFs can be read one page at a time.",
+        json!({
+            "type": "object",
+            "required": ["ticker", "path"],
+            "properties": {
+                "ticker": { "type": "string" },
+                "path": { "type": "string", "description": "Path inside the ticker folder, e.g. reports/10-K.pdf" },
+                "page": { "type": "integer", "description": "Page of a PDF to read, starting at 1" }
+            }
+        }),
+        move |arguments| {
+            let ticker = arguments["ticker"].as_str().ok_or("ticker is required")?;
+            let path = arguments["path"].as_str().ok_or("path is required")?;
+            let path = within(&within(&root, ticker)?, path)?;
+
+            match arguments["page"].as_u64() {
+                Some(page) => pdf_pages(&path)?.into_iter()
+                    .find(|(i, _)| *i as u64 + 1 == page)
+                    .map(|(_, text)| text)
+                    .ok_or_else(|| format!("{} has no text on page {}", path.display(), page).into()),
+                None => read_attachment(&path)
+            }
+        }
+    );
+
+    tools
+}
+
+/// `relative` inside `root`, refusing anything that would climb out of it.
+fn within(root: &Path, relative: &str) -> Result<PathBuf, GenericError> {
+    let relative = Path::new(relative);
+
+    if !relative.components().all(|component| matches!(component, Component::Normal(_) | Component::CurDir)) {
+        return Err(format!("{} is outside the data folder", relative.display()).into());
+    }
+
+    Ok(root.join(relative))
+}
+
+/// Files under `dir`, descending `depth` levels into sub folders.
+fn list_files(dir: &Path, base: &Path, depth: usize) -> Result<String, GenericError> {
+    let mut entries: Vec<PathBuf> = std::fs::read_dir(dir)
+        .map_err(|e| format!("could not list {}: {}", dir.display(), e))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .collect();
+    entries.sort();
+
+    let mut listing = String::new();
+
+    for path in entries {
+        let name = path.strip_prefix(base).unwrap_or(&path).display().to_string();
+        if path.is_dir() {
+            listing.push_str(&format!("{}/\n", name));
+            if depth > 0 {
+                listing.push_str(&list_files(&path, base, depth - 1)?);
+            }
+        } else {
+            listing.push_str(&format!("{}\n", name));
+        }
+    }
+
+    Ok(listing)
+}
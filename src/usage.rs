@@ -0,0 +1,236 @@
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use chrono::{DateTime, Local, NaiveDate, Utc};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::backend::Usage;
+use crate::models::ModelInfo;
+use crate::GenericError;
+
+/// One call to a model, as kept in the ledger.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LedgerEntry {
+    pub time: DateTime<Utc>,
+    /// Subcommand that made the call, e.g. `query` or `finance`.
+    pub command: String,
+    pub model: String,
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    /// In USD, `None` for models without prices.
+    pub cost: Option<f64>,
+    /// The provider reported no usage, so the tokens were counted locally.
+    #[serde(default)]
+    pub estimated: bool
+}
+
+/// Adds up the calls made during one run, and appends each of them to the
+/// ledger file that `usage` reports from. Clones of an `LLM` share one meter.
+#[derive(Default)]
+pub struct UsageMeter {
+    command: String,
+    ledger: Option<PathBuf>,
+    entries: Mutex<Vec<LedgerEntry>>
+}
+
+impl UsageMeter {
+    pub fn new(command: &str, ledger: PathBuf) -> Self {
+        UsageMeter { command: command.to_string(), ledger: Some(ledger), entries: Mutex::new(Vec::new()) }
+    }
+
+    pub fn record(&self, model: &ModelInfo, usage: Usage, estimated: bool) {
+        let entry = LedgerEntry {
+            time: Utc::now(),
+            command: self.command.clone(),
+            model: model.id.clone(),
+            prompt_tokens: usage.prompt_tokens,
+            completion_tokens: usage.completion_tokens,
+            cost: model.cost(&usage),
+            estimated
+        };
+
+        // Losing a ledger line shouldn't cost the answer it was paid for
+        if let Some(ledger) = &self.ledger {
+            if let Err(e) = append(ledger, &entry) {
+                eprintln!("WARNING: could not write to the usage ledger {}: {}", ledger.display(), e);
+            }
+        }
+
+        self.entries.lock().unwrap().push(entry);
+    }
+
+    /// Per model totals of this run, `None` if no calls were made.
+    pub fn summary(&self) -> Option<String> {
+        let entries = self.entries.lock().unwrap();
+        if entries.is_empty() {
+            return None;
+        }
+        Some(format!("Usage:\n{}", table("MODEL", &totals(&entries, |entry| entry.model.clone()))))
+    }
+}
+
+fn append(ledger: &Path, entry: &LedgerEntry) -> Result<(), GenericError> {
+    if let Some(dir) = ledger.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(ledger)?;
+    writeln!(file, "{}", serde_json::to_string(entry)?)?;
+    Ok(())
+}
+
+/// All entries of the ledger at `path`, none if it doesn't exist yet.
+pub fn read_ledger(path: &Path) -> Result<Vec<LedgerEntry>, GenericError> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    std::fs::read_to_string(path)?
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| serde_json::from_str(line)
+            .map_err(|e| format!("{} line {}: {}", path.display(), i + 1, e).into()))
+        .collect()
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum GroupBy {
+    Day,
+    Month,
+    Model,
+    Command
+}
+
+/// Spend in `entries` since `since`, one row per group.
+pub fn report(entries: &[LedgerEntry], since: Option<NaiveDate>, by: GroupBy) -> String {
+    let day = |entry: &LedgerEntry| entry.time.with_timezone(&Local).date_naive();
+    let entries: Vec<LedgerEntry> = entries.iter()
+        .filter(|entry| since.is_none_or(|since| day(entry) >= since))
+        .cloned()
+        .collect();
+
+    let (title, groups) = match by {
+        GroupBy::Day => ("DAY", totals(&entries, |entry| day(entry).format("%Y-%m-%d").to_string())),
+        GroupBy::Month => ("MONTH", totals(&entries, |entry| day(entry).format("%Y-%m").to_string())),
+        GroupBy::Model => ("MODEL", totals(&entries, |entry| entry.model.clone())),
+        GroupBy::Command => ("COMMAND", totals(&entries, |entry| entry.command.clone()))
+    };
+
+    table(title, &groups)
+}
+
+#[derive(Default, Clone, Debug, PartialEq)]
+struct Totals {
+    calls: usize,
+    prompt_tokens: u64,
+    completion_tokens: u64,
+    cost: f64,
+    /// Calls to models without prices, left out of `cost`.
+    unpriced: usize,
+    estimated: bool
+}
+
+impl Totals {
+    fn add(&mut self, entry: &LedgerEntry) {
+        self.calls += 1;
+        self.prompt_tokens += entry.prompt_tokens as u64;
+        self.completion_tokens += entry.completion_tokens as u64;
+        match entry.cost {
+            Some(cost) => self.cost += cost,
+            None => self.unpriced += 1
+        }
+        self.estimated |= entry.estimated;
+    }
+}
+
+fn totals(entries: &[LedgerEntry], key: impl Fn(&LedgerEntry) -> String) -> BTreeMap<String, Totals> {
+    let mut groups: BTreeMap<String, Totals> = BTreeMap::new();
+    for entry in entries {
+        groups.entry(key(entry)).or_default().add(entry);
+    }
+    groups
+}
+
+fn table(title: &str, groups: &BTreeMap<String, Totals>) -> String {
+    let mut total = Totals::default();
+    let mut table = format!("{:<30} {:>6} {:>12} {:>12} {:>10}\n", title, "CALLS", "PROMPT", "COMPLETION", "COST");
+
+    for (name, totals) in groups {
+        table.push_str(&row(name, totals));
+        total.calls += totals.calls;
+        total.prompt_tokens += totals.prompt_tokens;
+        total.completion_tokens += totals.completion_tokens;
+        total.cost += totals.cost;
+        total.unpriced += totals.unpriced;
+        total.estimated |= totals.estimated;
+    }
+    table.push_str(&row("total", &total));
+
+    if total.estimated {
+        table.push_str("~ some token counts are estimates, the provider didn't report them\n");
+    }
+    if total.unpriced > 0 {
+        table.push_str(&format!("{} calls to models without prices are not in the cost\n", total.unpriced));
+    }
+
+    table
+}
+
+fn row(name: &str, totals: &Totals) -> String {
+    let marker = if totals.estimated { "~" } else { "" };
+    let cost = if totals.unpriced == totals.calls { "-".to_string() } else { format!("${:.4}", totals.cost) };
+    format!("{:<30} {:>6} {:>12} {:>12} {:>10}\n", name, totals.calls,
+        format!("{}{}", marker, totals.prompt_tokens), format!("{}{}", marker, totals.completion_tokens), cost)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(time: &str, model: &str, prompt_tokens: u32, completion_tokens: u32, cost: Option<f64>) -> LedgerEntry {
+        LedgerEntry {
+            time: time.parse().unwrap(),
+            command: "query".to_string(),
+            model: model.to_string(),
+            prompt_tokens,
+            completion_tokens,
+            cost,
+            estimated: false
+        }
+    }
+
+    #[test]
+    fn report_groups_by_model_and_skips_older_calls() {
+        let entries = vec![
+            entry("2024-07-01T12:00:00Z", "gpt-4o", 1000, 100, Some(0.0035)),
+            entry("2024-07-03T12:00:00Z", "gpt-4o", 2000, 200, Some(0.007)),
+            entry("2024-07-03T12:00:00Z", "llama3.1", 500, 50, None),
+        ];
+
+        let report = report(&entries, NaiveDate::from_ymd_opt(2024, 7, 2), GroupBy::Model);
+        let lines: Vec<&str> = report.lines().collect();
+
+        assert_eq!(lines[1], format!("{:<30} {:>6} {:>12} {:>12} {:>10}", "gpt-4o", 1, 2000, 200, "$0.0070"));
+        assert_eq!(lines[2], format!("{:<30} {:>6} {:>12} {:>12} {:>10}", "llama3.1", 1, 500, 50, "-"));
+        assert_eq!(lines[3], format!("{:<30} {:>6} {:>12} {:>12} {:>10}", "total", 2, 2500, 250, "$0.0070"));
+        assert_eq!(lines[4], "1 calls to models without prices are not in the cost");
+    }
+
+    #[test]
+    fn ledger_round_trips() {
+        let path = std::env::temp_dir().join(format!("llm_search_ledger_{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let first = entry("2024-07-01T12:00:00Z", "gpt-4o", 1000, 100, Some(0.0035));
+        append(&path, &first).unwrap();
+        let mut second = entry("2024-07-02T12:00:00Z", "llama3.1", 10, 5, None);
+        second.estimated = true;
+        append(&path, &second).unwrap();
+
+        assert_eq!(read_ledger(&path).unwrap(), vec![first, second]);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,366 @@
+use serde::{Deserialize, Serialize};
+
+use crate::ratios::{self, spell_out};
+use crate::statements::{year, FinancialStatement};
+
+const SHARES: &[&str] = &["Diluted Average Shares", "Diluted Weighted Average Shares", "Weighted Average Diluted Shares Outstanding", "Shares Outstanding", "Ordinary Shares Number", "Share Issued"];
+
+/// How far the growth taken from the statements' history may go either way,
+/// so one exceptional year doesn't carry the whole forecast.
+const HISTORICAL_GROWTH: (f64, f64) = (-0.05, 0.15);
+
+/// `[valuation]` in the config: the assumptions of the discounted cash flow
+/// model, rates as fractions (0.09 is 9%).
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ValuationConfig {
+    /// Weighted average cost of capital, what the cash flows are discounted at.
+    pub wacc: f64,
+    /// Growth of the free cash flow forever after the forecast.
+    pub terminal_growth: f64,
+    /// Years forecast one by one before the terminal value takes over.
+    pub years: usize,
+    /// Yearly free cash flow growth over the forecast. Taken from the
+    /// statements' history when left out.
+    pub growth: Option<f64>,
+    /// Valued next to each other; bear, base and bull cases when left out.
+    pub scenarios: Vec<ScenarioConfig>
+}
+
+impl Default for ValuationConfig {
+    fn default() -> Self {
+        ValuationConfig { wacc: 0.09, terminal_growth: 0.025, years: 5, growth: None, scenarios: Vec::new() }
+    }
+}
+
+/// A scenario's assumptions. Anything left out is the base assumption.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ScenarioConfig {
+    pub name: String,
+    pub growth: Option<f64>,
+    pub wacc: Option<f64>,
+    pub terminal_growth: Option<f64>
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct Assumptions {
+    pub growth: f64,
+    pub wacc: f64,
+    pub terminal_growth: f64,
+    pub years: usize
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct ForecastYear {
+    pub year: usize,
+    pub free_cash_flow: f64,
+    pub present_value: f64
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Scenario {
+    pub name: String,
+    pub assumptions: Assumptions,
+    pub forecast: Vec<ForecastYear>,
+    /// Value at the end of the forecast of every year after it.
+    pub terminal_value: f64,
+    pub terminal_present_value: f64,
+    pub enterprise_value: f64,
+    /// Enterprise value less net debt.
+    pub equity_value: Option<f64>,
+    pub per_share: Option<f64>
+}
+
+/// Value of the base scenario for a grid of WACCs (rows) and terminal growth
+/// rates (columns). Cells where the WACC isn't above the growth are empty.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Sensitivity {
+    /// What the cells hold: value per share, equity value or enterprise value.
+    pub measure: String,
+    pub waccs: Vec<f64>,
+    pub terminal_growths: Vec<f64>,
+    pub values: Vec<Vec<Option<f64>>>
+}
+
+/// A discounted cash flow valuation, written to `valuation.json`.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Valuation {
+    pub currency: Option<String>,
+    /// The period whose free cash flow the forecast starts from.
+    pub period: String,
+    pub free_cash_flow: f64,
+    pub historical_growth: Option<f64>,
+    pub net_debt: Option<f64>,
+    pub shares: Option<f64>,
+    pub scenarios: Vec<Scenario>,
+    pub sensitivity: Sensitivity
+}
+
+/// Values the company from the free cash flow of the newest period of its
+/// cash flow statement. Net debt comes from the balance sheet and the share
+/// count from the income statement or balance sheet; without them the
+/// valuation stops at the enterprise value.
+pub fn value(income: Option<&FinancialStatement>, balance_sheet: Option<&FinancialStatement>, cash_flow: Option<&FinancialStatement>, config: &ValuationConfig) -> Result<Valuation, String> {
+    let cash_flow = cash_flow.ok_or("there is no cash flow statement to value")?;
+    let column = cash_flow.newest().ok_or("the cash flow statement has no periods")?;
+    let period = cash_flow.periods[column].clone();
+
+    let free_cash_flow = ratios::free_cash_flow(cash_flow, column)
+        .ok_or_else(|| format!("the cash flow statement has no free cash flow, or operating cash flow and capital expenditure, for {}", period))?;
+    if free_cash_flow <= 0.0 {
+        return Err(format!("the free cash flow for {} is not positive, discounting it would value the company at nothing", period));
+    }
+    if config.years == 0 {
+        return Err("the forecast needs at least one year".to_string());
+    }
+
+    // The balance sheet and share count as of the same period, or else the newest
+    let latest = |statement: &FinancialStatement| statement.column(&period).or_else(|| statement.newest());
+    let net_debt = balance_sheet.and_then(|balance_sheet| {
+        let column = latest(balance_sheet)?;
+        let debt = ratios::total_debt(balance_sheet, column);
+        let cash = balance_sheet.value(ratios::CASH, column);
+        if debt.is_none() && cash.is_none() { None } else { Some(debt.unwrap_or(0.0) - cash.unwrap_or(0.0)) }
+    });
+    let shares = [income, balance_sheet].into_iter().flatten()
+        .find_map(|statement| statement.value(SHARES, latest(statement)?))
+        .filter(|shares| *shares > 0.0);
+
+    let historical_growth = growth_rate(cash_flow, |column| ratios::free_cash_flow(cash_flow, column))
+        .or_else(|| income.and_then(|income| growth_rate(income, |column| income.value(ratios::REVENUE, column))));
+
+    let base = Assumptions {
+        growth: config.growth
+            .or(historical_growth.map(|growth| growth.clamp(HISTORICAL_GROWTH.0, HISTORICAL_GROWTH.1)))
+            .unwrap_or(config.terminal_growth),
+        wacc: config.wacc,
+        terminal_growth: config.terminal_growth,
+        years: config.years
+    };
+
+    let scenarios = if config.scenarios.is_empty() {
+        vec![
+            ScenarioConfig { name: "bear".to_string(), growth: Some(base.growth - 0.03), wacc: Some(base.wacc + 0.01), terminal_growth: None },
+            ScenarioConfig { name: "base".to_string(), growth: None, wacc: None, terminal_growth: None },
+            ScenarioConfig { name: "bull".to_string(), growth: Some(base.growth + 0.03), wacc: Some(base.wacc - 0.01), terminal_growth: None }
+        ]
+    } else {
+        config.scenarios.clone()
+    };
+
+    let scenarios = scenarios.iter()
+        .map(|scenario| {
+            let assumptions = Assumptions {
+                growth: scenario.growth.unwrap_or(base.growth),
+                wacc: scenario.wacc.unwrap_or(base.wacc),
+                terminal_growth: scenario.terminal_growth.unwrap_or(base.terminal_growth),
+                ..base
+            };
+            discount(&scenario.name, free_cash_flow, assumptions, net_debt, shares)
+                .ok_or_else(|| format!("in the {} scenario the WACC of {:.1}% must be above the terminal growth of {:.1}%", scenario.name, assumptions.wacc * 100.0, assumptions.terminal_growth * 100.0))
+        })
+        .collect::<Result<Vec<Scenario>, String>>()?;
+
+    Ok(Valuation {
+        currency: cash_flow.currency.clone(),
+        period,
+        free_cash_flow,
+        historical_growth,
+        net_debt,
+        shares,
+        scenarios,
+        sensitivity: sensitivity(free_cash_flow, base, net_debt, shares)
+    })
+}
+
+/// The DCF of one scenario, `None` when the WACC isn't above the terminal
+/// growth and the terminal value would be infinite.
+fn discount(name: &str, free_cash_flow: f64, assumptions: Assumptions, net_debt: Option<f64>, shares: Option<f64>) -> Option<Scenario> {
+    let Assumptions { growth, wacc, terminal_growth, years } = assumptions;
+    if wacc <= terminal_growth || wacc <= -1.0 {
+        return None;
+    }
+
+    let forecast: Vec<ForecastYear> = (1..=years)
+        .map(|year| {
+            let free_cash_flow = free_cash_flow * (1.0 + growth).powi(year as i32);
+            ForecastYear { year, free_cash_flow, present_value: free_cash_flow / (1.0 + wacc).powi(year as i32) }
+        })
+        .collect();
+
+    let last = forecast.last()?.free_cash_flow;
+    let terminal_value = last * (1.0 + terminal_growth) / (wacc - terminal_growth);
+    let terminal_present_value = terminal_value / (1.0 + wacc).powi(years as i32);
+    let enterprise_value = forecast.iter().map(|year| year.present_value).sum::<f64>() + terminal_present_value;
+    let equity_value = net_debt.map(|net_debt| enterprise_value - net_debt);
+    let per_share = equity_value.zip(shares).map(|(equity, shares)| equity / shares);
+
+    Some(Scenario { name: name.to_string(), assumptions, forecast, terminal_value, terminal_present_value, enterprise_value, equity_value, per_share })
+}
+
+fn sensitivity(free_cash_flow: f64, base: Assumptions, net_debt: Option<f64>, shares: Option<f64>) -> Sensitivity {
+    let waccs: Vec<f64> = [-0.02, -0.01, 0.0, 0.01, 0.02].iter().map(|step| base.wacc + step).collect();
+    let terminal_growths: Vec<f64> = [-0.01, -0.005, 0.0, 0.005, 0.01].iter().map(|step| base.terminal_growth + step).collect();
+
+    let measure = match (net_debt, shares) {
+        (Some(_), Some(_)) => "value per share",
+        (Some(_), None) => "equity value",
+        _ => "enterprise value"
+    };
+
+    let values = waccs.iter()
+        .map(|wacc| {
+            terminal_growths.iter()
+                .map(|terminal_growth| {
+                    let scenario = discount("", free_cash_flow, Assumptions { wacc: *wacc, terminal_growth: *terminal_growth, ..base }, net_debt, shares)?;
+                    scenario.per_share.or(scenario.equity_value).or(Some(scenario.enterprise_value))
+                })
+                .collect()
+        })
+        .collect();
+
+    Sensitivity { measure: measure.to_string(), waccs, terminal_growths, values }
+}
+
+/// Compound yearly growth of `value` from the oldest to the newest year of
+/// `statement`, when both are positive.
+fn growth_rate(statement: &FinancialStatement, value: impl Fn(usize) -> Option<f64>) -> Option<f64> {
+    let dated: Vec<(u32, f64)> = statement.periods.iter().enumerate()
+        .filter_map(|(column, period)| Some((year(period)?, value(column)?)))
+        .collect();
+
+    let (oldest_year, oldest) = *dated.iter().min_by_key(|(year, _)| *year)?;
+    let (newest_year, newest) = *dated.iter().max_by_key(|(year, _)| *year)?;
+    if newest_year == oldest_year || oldest <= 0.0 || newest <= 0.0 {
+        return None;
+    }
+
+    Some((newest / oldest).powf(1.0 / (newest_year - oldest_year) as f64) - 1.0)
+}
+
+impl Valuation {
+    /// The numbers as Markdown tables.
+    pub fn to_markdown(&self) -> String {
+        let currency = self.currency.as_deref().unwrap_or("");
+        let amount = |value: Option<f64>| value.map_or("-".to_string(), |value| spell_out(value, currency));
+        let percent = |value: f64| format!("{:.1}%", value * 100.0);
+        let per_share = |value: Option<f64>| value.map_or("-".to_string(), |value| format!("{:.2} {}", value, currency).trim_end().to_string());
+
+        let mut markdown = String::from("## Inputs\n\n");
+        markdown.push_str(&format!("- Free cash flow ({}): {}\n", self.period, spell_out(self.free_cash_flow, currency)));
+        markdown.push_str(&format!("- Historical growth: {}\n", self.historical_growth.map_or("-".to_string(), percent)));
+        markdown.push_str(&format!("- Net debt: {}\n", amount(self.net_debt)));
+        markdown.push_str(&format!("- Shares: {}\n", self.shares.map_or("-".to_string(), |shares| spell_out(shares, ""))));
+
+        markdown.push_str("\n## Scenarios\n\n| Scenario | Growth | WACC | Terminal growth | Enterprise value | Equity value | Per share |\n|---|---|---|---|---|---|---|\n");
+        for scenario in &self.scenarios {
+            markdown.push_str(&format!("| {} | {} | {} | {} | {} | {} | {} |\n",
+                scenario.name, percent(scenario.assumptions.growth), percent(scenario.assumptions.wacc), percent(scenario.assumptions.terminal_growth),
+                spell_out(scenario.enterprise_value, currency), amount(scenario.equity_value), per_share(scenario.per_share)));
+        }
+
+        if let Some(scenario) = self.scenarios.iter().find(|scenario| scenario.name == "base").or(self.scenarios.first()) {
+            markdown.push_str(&format!("\n## Forecast ({})\n\n| Year | Free cash flow | Present value |\n|---|---|---|\n", scenario.name));
+            for year in &scenario.forecast {
+                markdown.push_str(&format!("| {} | {} | {} |\n", year.year, spell_out(year.free_cash_flow, currency), spell_out(year.present_value, currency)));
+            }
+            markdown.push_str(&format!("| Terminal | {} | {} |\n", spell_out(scenario.terminal_value, currency), spell_out(scenario.terminal_present_value, currency)));
+        }
+
+        let cell = |value: Option<f64>| match value {
+            Some(value) if self.sensitivity.measure == "value per share" => per_share(Some(value)),
+            value => amount(value)
+        };
+        markdown.push_str(&format!("\n## Sensitivity of the {}\n\n| WACC \\ Terminal growth |", self.sensitivity.measure));
+        for growth in &self.sensitivity.terminal_growths {
+            markdown.push_str(&format!(" {} |", percent(*growth)));
+        }
+        markdown.push_str(&format!("\n|---|{}\n", "---|".repeat(self.sensitivity.terminal_growths.len())));
+        for (wacc, row) in self.sensitivity.waccs.iter().zip(&self.sensitivity.values) {
+            markdown.push_str(&format!("| {} |", percent(*wacc)));
+            for value in row {
+                markdown.push_str(&format!(" {} |", cell(*value)));
+            }
+            markdown.push('\n');
+        }
+
+        markdown
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::statements::parse;
+
+    fn close(value: f64, expected: f64) -> bool {
+        (value - expected).abs() < 1e-6 * expected.abs().max(1.0)
+    }
+
+    #[test]
+    fn discounts_the_forecast_and_terminal_value() {
+        let assumptions = Assumptions { growth: 0.1, wacc: 0.1, terminal_growth: 0.02, years: 2 };
+
+        let scenario = discount("base", 100.0, assumptions, Some(50.0), Some(10.0)).unwrap();
+
+        // 110 / 1.1 + 121 / 1.21, then 121 * 1.02 / 0.08 discounted two years
+        assert!(close(scenario.forecast[1].free_cash_flow, 121.0));
+        assert!(close(scenario.forecast[0].present_value, 100.0));
+        assert!(close(scenario.terminal_value, 1542.75));
+        assert!(close(scenario.enterprise_value, 200.0 + 1542.75 / 1.21));
+        assert!(close(scenario.per_share.unwrap(), (scenario.enterprise_value - 50.0) / 10.0));
+
+        assert!(discount("broken", 100.0, Assumptions { terminal_growth: 0.1, ..assumptions }, None, None).is_none());
+    }
+
+    #[test]
+    fn values_the_statements_under_each_scenario() {
+        let cash_flow = parse("\
+In millions of USD
+Breakdown\tFY2023\tFY2022\tFY2021
+Operating Cash Flow\t1,300\t1,150\t1,000
+Capital Expenditure\t(200)\t(150)\t(100)
+").unwrap();
+        let balance_sheet = parse("\
+In millions of USD
+Breakdown\t12/31/2023
+Total Debt\t800
+Cash and Cash Equivalents\t300
+").unwrap();
+        let income = parse("\
+In millions
+Breakdown\tFY2023
+Diluted Average Shares\t100
+").unwrap();
+
+        let valuation = value(Some(&income), Some(&balance_sheet), Some(&cash_flow), &ValuationConfig::default()).unwrap();
+
+        assert_eq!(valuation.period, "FY2023");
+        assert!(close(valuation.free_cash_flow, 1_100e6));
+        // 900 to 1,100 over two years
+        assert!(close(valuation.historical_growth.unwrap(), (1100.0f64 / 900.0).sqrt() - 1.0));
+        assert!(close(valuation.net_debt.unwrap(), 500e6));
+        assert_eq!(valuation.shares, Some(100e6));
+
+        let names: Vec<&str> = valuation.scenarios.iter().map(|scenario| scenario.name.as_str()).collect();
+        assert_eq!(names, ["bear", "base", "bull"]);
+        let per_share: Vec<f64> = valuation.scenarios.iter().map(|scenario| scenario.per_share.unwrap()).collect();
+        assert!(per_share[0] < per_share[1] && per_share[1] < per_share[2]);
+
+        assert_eq!(valuation.sensitivity.measure, "value per share");
+        assert_eq!(valuation.sensitivity.values[2][2], valuation.scenarios[1].per_share);
+
+        let markdown = valuation.to_markdown();
+        assert!(markdown.contains("| base | 10.6% | 9.0% | 2.5% |"));
+        assert!(markdown.contains("## Sensitivity of the value per share"));
+    }
+
+    #[test]
+    fn negative_free_cash_flow_cannot_be_valued() {
+        let cash_flow = parse("Breakdown\tFY2023\nFree Cash Flow\t(50)\n").unwrap();
+
+        assert!(value(None, None, Some(&cash_flow), &ValuationConfig::default()).is_err());
+        assert!(value(None, None, None, &ValuationConfig::default()).is_err());
+    }
+}